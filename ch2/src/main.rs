@@ -16,7 +16,7 @@ use syscall::{Caller, SyscallId};
 // 用户程序内联进来。
 core::arch::global_asm!(include_str!(env!("APP_ASM")));
 // 定义内核入口。
-linker::boot0!(rust_main; stack = 4 * 4096);
+linker::boot0!(rust_main; stack = linker::stack_size(option_env!("KERNEL_STACK_PAGES"), 4));
 
 extern "C" fn rust_main() -> ! {
     // bss 段清零
@@ -3,7 +3,9 @@
 #![feature(naked_functions, asm_const)]
 #![deny(warnings)]
 
+mod sbi;
 mod task;
+mod time;
 
 #[macro_use]
 extern crate rcore_console;
@@ -11,7 +13,6 @@ extern crate rcore_console;
 use impls::{Console, SyscallContext};
 use rcore_console::log;
 use riscv::register::*;
-use sbi_rt::*;
 use task::TaskControlBlock;
 
 // 应用程序内联进来。
@@ -19,7 +20,7 @@ core::arch::global_asm!(include_str!(env!("APP_ASM")));
 // 应用程序数量。
 const APP_CAPACITY: usize = 32;
 // 定义内核入口。
-linker::boot0!(rust_main; stack = (APP_CAPACITY + 2) * 4096);
+linker::boot0!(rust_main; stack = linker::stack_size(option_env!("KERNEL_STACK_PAGES"), APP_CAPACITY + 2));
 
 extern "C" fn rust_main() -> ! {
     // bss 段清零
@@ -28,6 +29,9 @@ extern "C" fn rust_main() -> ! {
     rcore_console::init_console(&Console);
     rcore_console::set_log_level(option_env!("LOG"));
     rcore_console::test_log();
+    // 检查时钟
+    #[cfg(feature = "self-test")]
+    time::self_test();
     // 初始化 syscall
     syscall::init_io(&SyscallContext);
     syscall::init_process(&SyscallContext);
@@ -54,13 +58,13 @@ extern "C" fn rust_main() -> ! {
         if !tcb.finish {
             loop {
                 #[cfg(not(feature = "coop"))]
-                sbi_rt::set_timer(time::read64() + 12500);
+                time::set_next_trigger(time::INTERVAL);
                 unsafe { tcb.execute() };
 
                 use scause::*;
                 let finish = match scause::read().cause() {
                     Trap::Interrupt(Interrupt::SupervisorTimer) => {
-                        sbi_rt::set_timer(u64::MAX);
+                        time::cancel();
                         log::trace!("app{i} timeout");
                         false
                     }
@@ -100,16 +104,14 @@ extern "C" fn rust_main() -> ! {
         }
         i = (i + 1) % index_mod;
     }
-    system_reset(Shutdown, NoReason);
-    unreachable!()
+    sbi::shutdown()
 }
 
 /// Rust 异常处理函数，以异常方式关机。
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     println!("{info}");
-    system_reset(Shutdown, SystemFailure);
-    loop {}
+    sbi::abort()
 }
 
 /// 各种接口库的实现
@@ -121,8 +123,7 @@ mod impls {
     impl rcore_console::Console for Console {
         #[inline]
         fn put_char(&self, c: u8) {
-            #[allow(deprecated)]
-            sbi_rt::legacy::console_putchar(c as _);
+            crate::sbi::console_putchar(c);
         }
     }
 
@@ -168,7 +169,7 @@ mod impls {
         fn clock_gettime(&self, _caller: syscall::Caller, clock_id: ClockId, tp: usize) -> isize {
             match clock_id {
                 ClockId::CLOCK_MONOTONIC => {
-                    let time = riscv::register::time::read() * 10000 / 125;
+                    let time = crate::time::nanos() as usize;
                     *unsafe { &mut *(tp as *mut TimeSpec) } = TimeSpec {
                         tv_sec: time / 1_000_000_000,
                         tv_nsec: time % 1_000_000_000,
@@ -0,0 +1,28 @@
+//! SBI 调用的简单封装。
+
+/// 正常关机。
+#[inline]
+pub fn shutdown() -> ! {
+    sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::NoReason);
+    unreachable!()
+}
+
+/// 以异常方式关机。
+#[inline]
+pub fn abort() -> ! {
+    sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::SystemFailure);
+    loop {}
+}
+
+/// 设置在 `time` 寄存器达到 `ticks` 时触发时钟中断。
+#[inline]
+pub fn set_timer(ticks: u64) {
+    sbi_rt::set_timer(ticks);
+}
+
+/// 向控制台输出一个字符。
+#[inline]
+pub fn console_putchar(c: u8) {
+    #[allow(deprecated)]
+    sbi_rt::legacy::console_putchar(c as _);
+}
@@ -0,0 +1,53 @@
+//! 时钟和时钟中断。
+
+/// `time` 寄存器的频率，默认为 QEMU virt 的 12.5 MHz，可由 `TIMEBASE_FREQ` 指定。
+pub const TIMEBASE_FREQ: u64 = linker::frequency(option_env!("TIMEBASE_FREQ"), 12_500_000) as _;
+
+/// 读取当前时钟周期数。
+#[inline]
+pub fn read() -> u64 {
+    riscv::register::time::read64()
+}
+
+/// 读取当前时间，单位纳秒。
+#[inline]
+pub fn nanos() -> u64 {
+    (read() as u128 * 1_000_000_000 / TIMEBASE_FREQ as u128) as _
+}
+
+/// 检查时钟单调递增。检查失败时 panic。
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    let (t0, n0) = (read(), nanos());
+    while read() == t0 {
+        core::hint::spin_loop();
+    }
+    let (t1, n1) = (read(), nanos());
+    assert!(t0 < t1, "time went backwards: {t0} -> {t1}");
+    assert!(n0 <= n1, "nanos went backwards: {n0} -> {n1}");
+}
+
+/// 每秒的时钟中断次数，默认为 1000，可由 `TICKS_PER_SEC` 指定。
+#[cfg(not(feature = "coop"))]
+pub const TICKS_PER_SEC: u64 = linker::frequency(option_env!("TICKS_PER_SEC"), 1000) as _;
+
+/// 一个时间片的时钟周期数。
+#[cfg(not(feature = "coop"))]
+pub const INTERVAL: u64 = TIMEBASE_FREQ / TICKS_PER_SEC;
+
+// 时间片至少要有一个时钟周期。
+#[cfg(not(feature = "coop"))]
+const _: () = assert!(INTERVAL > 0, "TICKS_PER_SEC exceeds TIMEBASE_FREQ");
+
+/// 设置 `interval` 个时钟周期后触发时钟中断。
+#[cfg(not(feature = "coop"))]
+#[inline]
+pub fn set_next_trigger(interval: u64) {
+    crate::sbi::set_timer(read() + interval);
+}
+
+/// 取消时钟中断。
+#[inline]
+pub fn cancel() {
+    crate::sbi::set_timer(u64::MAX);
+}
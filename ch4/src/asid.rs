@@ -0,0 +1,75 @@
+//! 地址空间标识（ASID）的分配。
+//!
+//! 硬件支持 ASID 时，每个进程使用不同的 ASID，切换地址空间时不需要刷新其他地址空间的地址转换缓存。
+//! ASID 耗尽或硬件不支持时，进程共用 [`Asid::SHARED`]，退化为切换时全部刷新。
+
+use crate::mmu;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use riscv::register::satp;
+
+/// 地址空间标识。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Asid(u16);
+
+impl Asid {
+    /// 内核和分配不到 ASID 的进程共用的 ASID。
+    pub const SHARED: Self = Self(0);
+
+    /// 获取 ASID 的值。
+    #[inline]
+    pub const fn val(self) -> u16 {
+        self.0
+    }
+}
+
+/// 位图能管理的 ASID 数量。
+const CAPACITY: usize = u64::BITS as _;
+
+/// 已分配的 ASID 位图，0 号保留。
+static USED: AtomicU64 = AtomicU64::new(1);
+
+/// 硬件支持的 ASID 数量，不超过 [`CAPACITY`]。
+static LIMIT: AtomicUsize = AtomicUsize::new(1);
+
+/// 探测硬件支持的 ASID 位数。
+///
+/// 向 satp 的 ASID 字段写入全 1，读回的值中保留的位就是硬件实现的位。需要在内核地址空间激活后调用。
+pub fn init() {
+    let old = mmu::current_token();
+    let probe = old | (0xffff << 44);
+    let bits = unsafe {
+        core::arch::asm!("csrw satp, {}", in(reg) probe);
+        let bits = satp::read().bits();
+        core::arch::asm!("csrw satp, {}", in(reg) old);
+        bits
+    };
+    mmu::flush_tlb();
+    let (_, asid_max, _) = mmu::decode_satp(bits);
+    LIMIT.store((asid_max as usize + 1).min(CAPACITY), Ordering::Relaxed);
+}
+
+/// 分配一个 ASID，耗尽时返回 [`Asid::SHARED`]。
+///
+/// 新分配的 ASID 可能刚被回收，因此先刷新它的地址转换缓存。
+pub fn alloc() -> Asid {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    let mut free = 0;
+    // 在位图中占用最低的空闲位，与其他分配竞争失败时重试
+    let claimed = USED.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+        free = (!used).trailing_zeros() as usize;
+        (free < limit).then_some(used | 1 << free)
+    });
+    if claimed.is_err() {
+        return Asid::SHARED;
+    }
+    let asid = Asid(free as _);
+    mmu::flush_tlb_asid(asid);
+    asid
+}
+
+/// 回收 ASID。
+pub fn dealloc(asid: Asid) {
+    if asid != Asid::SHARED {
+        USED.fetch_and(!(1 << asid.0), Ordering::Release);
+    }
+}
@@ -0,0 +1,101 @@
+//! 从 SBI 传来的设备树中读取内存范围。
+//!
+//! 这不是完整的设备树解析器：只遍历结构块，读取根节点的 `#address-cells`、`#size-cells`
+//! 和第一个 `/memory` 节点的 `reg` 属性，不处理 `memreserve`、`ranges` 等其他内容。
+
+/// 设备树头的魔数。
+const MAGIC: u32 = 0xd00d_feed;
+
+const BEGIN_NODE: u32 = 1;
+const END_NODE: u32 = 2;
+const PROP: u32 = 3;
+const NOP: u32 = 4;
+const END: u32 = 9;
+
+/// 解析设备树，返回主存的基地址和字节数。
+///
+/// `dtb` 为 0、魔数不对、结构块越界或找不到 `/memory` 节点时返回 `None`。
+pub fn parse_memory(dtb: usize) -> Option<(usize, usize)> {
+    if dtb == 0 || read_u32(dtb) != MAGIC {
+        return None;
+    }
+    let total = read_u32(dtb + 4) as usize;
+    let structs = dtb + read_u32(dtb + 8) as usize;
+    let strings = dtb + read_u32(dtb + 12) as usize;
+    let end = dtb + total;
+
+    // 规范规定的默认值
+    let mut address_cells = 2;
+    let mut size_cells = 1;
+    let mut depth = 0usize;
+    let mut in_memory = false;
+    let mut ptr = structs;
+    while ptr + 4 <= end {
+        let token = read_u32(ptr);
+        ptr += 4;
+        match token {
+            BEGIN_NODE => {
+                let name = read_cstr(ptr, end);
+                ptr = align4(ptr + name.len() + 1);
+                depth += 1;
+                in_memory = depth == 2 && (name == b"memory" || name.starts_with(b"memory@"));
+            }
+            END_NODE => {
+                depth = depth.checked_sub(1)?;
+                in_memory = false;
+            }
+            PROP => {
+                // 属性头、属性值和属性名都必须在设备树之内，否则设备树已损坏
+                if ptr + 8 > end {
+                    return None;
+                }
+                let len = read_u32(ptr) as usize;
+                let name_off = strings.checked_add(read_u32(ptr + 4) as usize)?;
+                let value = ptr + 8;
+                if value.checked_add(len)? > end || name_off >= end {
+                    return None;
+                }
+                let name = read_cstr(name_off, end);
+                ptr = align4(value + len);
+                if depth == 1 && name == b"#address-cells" && len >= 4 {
+                    address_cells = read_u32(value) as usize;
+                } else if depth == 1 && name == b"#size-cells" && len >= 4 {
+                    size_cells = read_u32(value) as usize;
+                } else if in_memory && name == b"reg" && len >= (address_cells + size_cells) * 4 {
+                    let base = read_cells(value, address_cells);
+                    let size = read_cells(value + address_cells * 4, size_cells);
+                    return Some((base, size));
+                }
+            }
+            NOP => {}
+            END => break,
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// 读取大端序的 32 位数。
+#[inline]
+fn read_u32(addr: usize) -> u32 {
+    u32::from_be(unsafe { (addr as *const u32).read_unaligned() })
+}
+
+/// 读取 `cells` 个 32 位单元组成的数。
+fn read_cells(addr: usize, cells: usize) -> usize {
+    (0..cells).fold(0, |n, i| (n << 32) | read_u32(addr + i * 4) as usize)
+}
+
+/// 读取以 0 结尾的字符串，不包括结尾的 0。
+fn read_cstr(addr: usize, end: usize) -> &'static [u8] {
+    let mut len = 0;
+    while addr + len < end && unsafe { *((addr + len) as *const u8) } != 0 {
+        len += 1;
+    }
+    unsafe { core::slice::from_raw_parts(addr as *const u8, len) }
+}
+
+#[inline]
+const fn align4(addr: usize) -> usize {
+    (addr + 3) & !3
+}
@@ -4,7 +4,13 @@
 //#![feature(default_alloc_error_handler)]
 #![deny(warnings)]
 
+mod asid;
+mod dtb;
+mod mmu;
 mod process;
+mod sbi;
+mod time;
+mod trap;
 
 #[macro_use]
 extern crate rcore_console;
@@ -12,103 +18,281 @@ extern crate rcore_console;
 extern crate alloc;
 
 use crate::{
-    impls::{Sv39Manager, SyscallContext},
-    process::Process,
+    asid::Asid,
+    impls::{SvManager, SyscallContext},
+    process::{self, Process},
 };
 use alloc::{alloc::alloc, vec::Vec};
 use core::alloc::Layout;
 use impls::Console;
 use kernel_context::{foreign::MultislotPortal, LocalContext};
 use kernel_vm::{
-    page_table::{MmuMeta, Sv39, VAddr, VmFlags, VmMeta, PPN, VPN},
-    AddressSpace,
+    page_table::{MmuMeta, VAddr, VmFlags, VmMeta, PPN, VPN},
+    AddressSpace, FlagsBuilder, MappedRegion, PteDisplay,
 };
 use rcore_console::log;
 use riscv::register::*;
-use sbi_rt::*;
 use syscall::Caller;
 use xmas_elf::ElfFile;
 
 // 应用程序内联进来。
 core::arch::global_asm!(include_str!(env!("APP_ASM")));
 // 定义内核入口。
-linker::boot0!(rust_main; stack = 6 * 4096);
-// 物理内存容量 = 24 MiB。
-const MEMORY: usize = 24 << 20;
+linker::boot0!(rust_main; stack = linker::stack_size(option_env!("KERNEL_STACK_PAGES"), 6));
+// 分页模式。
+#[cfg(not(feature = "sv48"))]
+type Meta = kernel_vm::page_table::Sv39;
+#[cfg(feature = "sv48")]
+type Meta = kernel_vm::page_table::Sv48;
+// 写入 satp 的分页模式。
+#[cfg(not(feature = "sv48"))]
+const SATP_MODE: satp::Mode = satp::Mode::Sv39;
+#[cfg(feature = "sv48")]
+const SATP_MODE: satp::Mode = satp::Mode::Sv48;
+// 设备树中找不到内存时使用的物理内存容量，默认 24 MiB，可由 `KERNEL_MEMORY_MIB` 指定。
+const MEMORY: usize = linker::memory_size(option_env!("KERNEL_MEMORY_MIB"), 24);
 // 传送门所在虚页。
-const PROTAL_TRANSIT: VPN<Sv39> = VPN::MAX;
+const PROTAL_TRANSIT: VPN<Meta> = VPN::MAX;
+// QEMU virt 的 UART 基地址。
+const UART: usize = 0x1000_0000;
+// 恒等映射到内核地址空间的设备：物理页号、页数、页属性。映射时总是设置 G 位。
+const MMIO: &[(usize, usize, VmFlags<Meta>)] = &[
+    (UART >> 12, 1, VmFlags::build_from_str("_WRV")),
+    (0x0200_0000 >> 12, 16, VmFlags::build_from_str("_WRV")), // CLINT
+    (0x0c00_0000 >> 12, 0x400, VmFlags::build_from_str("_WRV")), // PLIC
+];
 // 进程列表。
 static mut PROCESSES: Vec<Process> = Vec::new();
 
-extern "C" fn rust_main() -> ! {
+extern "C" fn rust_main(_hartid: usize, dtb: usize) -> ! {
     let layout = linker::KernelLayout::locate();
     // bss 段清零
     unsafe { layout.zero_bss() };
+    // 启用分页之前推算内核虚地址与物理地址之差
+    mmu::init_offset(&layout);
     // 初始化 `console`
+    sbi::init();
     rcore_console::init_console(&Console);
     rcore_console::set_log_level(option_env!("LOG"));
     rcore_console::test_log();
+    let mut stopwatch = time::Stopwatch::start();
+    // 从设备树中找到内核之后的内存，不覆盖设备树本身
+    let memory = match dtb::parse_memory(dtb) {
+        Some((base, size)) if base <= layout.start() && layout.end() < base + size => {
+            let end = if dtb > layout.end() {
+                dtb.min(base + size)
+            } else {
+                base + size
+            };
+            log::info!("memory from device tree: {base:#x}..{:#x}", base + size);
+            (end & !((1 << Meta::PAGE_BITS) - 1)) - layout.start()
+        }
+        _ => MEMORY,
+    };
     // 初始化内核堆
-    kernel_alloc::init(layout.start() as _);
+    kernel_alloc::try_init(layout.start() as _).expect("invalid heap base");
+    kernel_alloc::set_alloc_error_hook(alloc_error);
     unsafe {
-        kernel_alloc::transfer(core::slice::from_raw_parts_mut(
+        kernel_alloc::try_transfer(core::slice::from_raw_parts_mut(
             layout.end() as _,
-            MEMORY - layout.len(),
+            memory - layout.len(),
         ))
-    };
+    }
+    .expect("invalid heap region");
+    // 检查时钟
+    #[cfg(feature = "self-test")]
+    time::self_test();
+    // 检查堆分配器
+    #[cfg(feature = "self-test")]
+    kernel_alloc::self_test(10_000, 0x2545_f491_4f6c_dd1d);
+    stopwatch.lap("heap init");
     // 建立异界传送门
     let portal_size = MultislotPortal::calculate_size(1);
-    let portal_layout = Layout::from_size_align(portal_size, 1 << Sv39::PAGE_BITS).unwrap();
+    let portal_layout = Layout::from_size_align(portal_size, 1 << Meta::PAGE_BITS).unwrap();
     let portal_ptr = unsafe { alloc(portal_layout) };
-    assert!(portal_layout.size() < 1 << Sv39::PAGE_BITS);
-    // 建立内核地址空间
-    let mut ks = kernel_space(layout, MEMORY, portal_ptr as _);
-    let portal_idx = PROTAL_TRANSIT.index_in(Sv39::MAX_LEVEL);
+    assert!(portal_layout.size() < 1 << Meta::PAGE_BITS);
+    // 检查页表遍历逻辑
+    #[cfg(feature = "self-test")]
+    kernel_vm::self_test::<Meta, SvManager>();
+    // 检查 satp 的拼合与拆分互逆
+    #[cfg(feature = "self-test")]
+    for (asid, ppn) in [(0, 0), (1, 0x8_0200), (u16::MAX, (1 << 44) - 1)] {
+        let info = mmu::SatpInfo::decode(mmu::make_satp(asid, PPN::new(ppn)));
+        let mode = match SATP_MODE {
+            satp::Mode::Sv48 => mmu::SatpMode::Sv48,
+            _ => mmu::SatpMode::Sv39,
+        };
+        assert_eq!(info.mode, mode);
+        assert_eq!(info.asid, asid);
+        assert_eq!(info.ppn.val(), ppn);
+    }
+    // 建立内核地址空间，传送门页映射到 `TRAMPOLINE_VADDR`
+    debug_assert_eq!(PROTAL_TRANSIT.base().val(), trap::TRAMPOLINE_VADDR);
+    // 传送门页装载的是代码，起始位置在用户地址空间中就是传送门所在的虚地址
+    let portal_text: fn() = unsafe { core::mem::transmute(portal_ptr) };
+    debug_assert_eq!(trap::trampoline_offset(portal_text), trap::TRAMPOLINE_VADDR);
+    let portal = MappedRegion {
+        vpn: PROTAL_TRANSIT..PROTAL_TRANSIT + 1,
+        ppn: mmu::ppn_of(portal_ptr),
+        flags: VmFlags::build_from_str("__G_XWRV"),
+    };
+    let mut ks = kernel_space(layout, memory, &[portal]);
+    // 内核地址空间激活后才能探测 ASID
+    asid::init();
+    stopwatch.lap("kernel space");
+    let portal_idx = PROTAL_TRANSIT.index_in(Meta::MAX_LEVEL);
+    // 只构造一次，使用检查参数的版本
+    let kernel_root = kernel_vm::try_page_table::<Meta>(
+        core::ptr::NonNull::new(mmu::virt_of(ks.root_ppn().val() << Meta::PAGE_BITS).as_mut_ptr())
+            .unwrap(),
+        VPN::ZERO,
+        Meta::MAX_LEVEL,
+    )
+    .unwrap_or_else(|e| panic!("invalid kernel root table: {e}"));
+    // 检查设备映射：UART 可写且用户不可访问
+    let (_, uart) = ks.translate_phys(VAddr::new(UART)).unwrap();
+    assert!(uart.contains(VmFlags::build_from_str("W__")));
+    assert!(!uart.contains(VmFlags::build_from_str("U____")));
+    // 检查堆映射：堆的最后一个字节可写
+    let (paddr, heap) = ks
+        .translate_phys(VAddr::new(layout.start() + memory - 1))
+        .expect("heap not mapped");
+    assert_eq!(paddr, mmu::phys_of((layout.start() + memory - 1) as _));
+    assert!(heap.contains(VmFlags::build_from_str("W__")));
+    // 检查内核代码映射为全局页
+    #[cfg(feature = "global_kernel")]
+    {
+        let (_, text) = ks.translate_phys(VAddr::new(rust_main as usize)).unwrap();
+        assert!(text.contains(VmFlags::build_from_str("G_____")));
+    }
+    // 统计加载应用程序时的分配
+    #[cfg(feature = "alloc_stats")]
+    kernel_alloc::reset_histogram();
     // 加载应用程序
-    for (i, elf) in linker::AppMeta::locate().iter().enumerate() {
-        let base = elf.as_ptr() as usize;
-        log::info!("detect app[{i}]: {base:#x}..{:#x}", base + elf.len());
-        if let Some(process) = Process::new(ElfFile::new(elf).unwrap()) {
-            // 映射异界传送门
-            process.address_space.root()[portal_idx] = ks.root()[portal_idx];
-            unsafe { PROCESSES.push(process) };
+    let apps = linker::AppMeta::locate();
+    log::info!("{} apps linked", apps.count());
+    // 检查损坏的应用程序被跳过而不影响其他应用程序
+    #[cfg(feature = "self-test")]
+    {
+        static BAD: [u8; 64] = {
+            let mut bad = [0; 64];
+            (bad[0], bad[1], bad[2], bad[3]) = (0x7f, b'E', b'L', b'G');
+            bad
+        };
+        let valid = apps
+            .iter()
+            .chain(core::iter::once(&BAD[..]))
+            .enumerate()
+            .filter_map(|(i, elf)| process::parse_app(i, elf))
+            .count();
+        assert_eq!(
+            valid,
+            apps.iter().filter(|elf| ElfFile::new(elf).is_ok()).count()
+        );
+    }
+    // 加载之前检查内存是否足够，以免加载到一半时耗尽
+    let plans = process::survey_apps(apps);
+    let need = plans.iter().map(|plan| plan.pages).sum::<usize>();
+    let have = kernel_alloc::free_page_count();
+    if need > have {
+        log::error!("insufficient memory: need {need}, have {have} pages");
+    } else {
+        for plan in &plans {
+            let i = plan.index;
+            let base = plan.elf.input.as_ptr() as usize;
+            log::info!(
+                "detect app[{i}]: {base:#x}..{:#x}, entry {:#x}, {} pages",
+                base + plan.elf.input.len(),
+                plan.summary.entry,
+                plan.pages
+            );
+            if let Some(m) = apps.manifest(i) {
+                log::info!(
+                    "app[{i}] {}: exit code {:?}, timeout {:?} ticks",
+                    m.name,
+                    m.exit_code,
+                    m.timeout_ticks
+                );
+            }
+            match Process::new(plan) {
+                Ok(process) => {
+                    // 映射异界传送门
+                    process.address_space.root()[portal_idx] = kernel_root[portal_idx];
+                    // 带 G 位的内核页必须在所有地址空间中都存在，因此共享给用户地址空间
+                    #[cfg(feature = "global_kernel")]
+                    let process = {
+                        let mut process = process;
+                        ks.clone_global_entries(&mut process.address_space);
+                        process
+                    };
+                    unsafe { PROCESSES.push(process) };
+                }
+                Err(e) => log::error!("app[{i}] failed to load: {e}"),
+            }
+        }
+    }
+    log::info!(
+        "{} of {} apps loaded",
+        unsafe { PROCESSES.len() },
+        apps.count()
+    );
+    stopwatch.lap("load apps");
+    #[cfg(feature = "alloc_stats")]
+    for (order, count) in kernel_alloc::alloc_histogram().into_iter().enumerate() {
+        if count != 0 {
+            log::debug!("live allocations of order {order}: {count}");
         }
     }
+    // 统计内核堆用量
+    let heap = kernel_alloc::heap_stats();
+    log::info!(
+        "heap usage: {:#x} / {:#x} bytes, peak {:#x}",
+        heap.allocated_bytes,
+        heap.total_bytes,
+        heap.peak_allocated_bytes
+    );
 
     // 建立调度栈
     const PAGE: Layout =
-        unsafe { Layout::from_size_align_unchecked(2 << Sv39::PAGE_BITS, 1 << Sv39::PAGE_BITS) };
+        unsafe { Layout::from_size_align_unchecked(2 << Meta::PAGE_BITS, 1 << Meta::PAGE_BITS) };
     let pages = 2;
     let stack = unsafe { alloc(PAGE) };
+    let range = VPN::new((1 << 26) - pages)..VPN::new(1 << 26);
     ks.map_extern(
-        VPN::new((1 << 26) - pages)..VPN::new(1 << 26),
-        PPN::new(stack as usize >> Sv39::PAGE_BITS),
+        range.clone(),
+        mmu::ppn_of(stack),
         VmFlags::build_from_str("_WRV"),
     );
+    // 内核地址空间已经激活，修改后要刷新
+    for vpn in kernel_vm::vpn_range(range.start, pages) {
+        mmu::flush_tlb_vaddr(vpn.base().val());
+    }
     // 建立调度线程，目的是划分异常域。调度线程上发生内核异常时会回到这个控制流处理
     let mut scheduling = LocalContext::thread(schedule as _, false);
     *scheduling.sp_mut() = 1 << 38;
     unsafe { scheduling.execute() };
-    log::error!("stval = {:#x}", stval::read());
-    panic!("trap from scheduling thread: {:?}", scause::read().cause());
+    panic!(
+        "trap from scheduling thread: {}",
+        trap::FaultInfo::current()
+    );
 }
 
 extern "C" fn schedule() -> ! {
     // 初始化异界传送门
-    let portal = unsafe { MultislotPortal::init_transit(PROTAL_TRANSIT.base().val(), 1) };
+    let portal = unsafe { MultislotPortal::init_transit(trap::TRAMPOLINE_VADDR, 1) };
     // 初始化 syscall
     syscall::init_io(&SyscallContext);
     syscall::init_process(&SyscallContext);
     syscall::init_scheduling(&SyscallContext);
     syscall::init_clock(&SyscallContext);
     while !unsafe { PROCESSES.is_empty() } {
-        let ctx = unsafe { &mut PROCESSES[0].context };
-        unsafe { ctx.execute(portal, ()) };
+        let process = unsafe { &mut PROCESSES[0] };
+        unsafe { process.context.execute(portal, ()) };
         match scause::read().cause() {
             scause::Trap::Exception(scause::Exception::UserEnvCall) => {
                 use syscall::{SyscallId as Id, SyscallResult as Ret};
 
-                let ctx = &mut ctx.context;
+                let ctx = &mut process.context.context;
                 let id: Id = ctx.a(7).into();
                 let args = [ctx.a(0), ctx.a(1), ctx.a(2), ctx.a(3), ctx.a(4), ctx.a(5)];
                 match syscall::handle(Caller { entity: 0, flow: 0 }, id, args) {
@@ -127,145 +311,251 @@ extern "C" fn schedule() -> ! {
                     }
                 }
             }
+            scause::Trap::Exception(
+                scause::Exception::LoadPageFault | scause::Exception::StorePageFault,
+            ) if process.handle_lazy_fault(stval::read()) => {
+                // 按需分配了栈页，回到用户态重新执行出错的指令
+            }
             e => {
                 log::error!(
-                    "unsupported trap: {e:?}, stval = {:#x}, sepc = {:#x}",
-                    stval::read(),
-                    ctx.context.pc()
+                    "unsupported trap: {}, sepc = {:#x}",
+                    trap::FaultInfo::current(),
+                    process.context.context.pc()
                 );
+                // 缺页时打印翻译路径，以区分缺少中间页表还是缺少叶子页表项
+                use scause::{Exception as E, Trap};
+                if let Trap::Exception(
+                    E::LoadPageFault | E::StorePageFault | E::InstructionPageFault,
+                ) = e
+                {
+                    let space = &process.address_space;
+                    let vaddr = VAddr::new(stval::read());
+                    log::error!("  indices: {}", kernel_vm::vpn_indices(vaddr));
+                    let trace = space.resolve_path(vaddr);
+                    for (level, pte) in &trace.ptes {
+                        log::error!("  level {level}: {}", PteDisplay(*pte));
+                    }
+                    log::error!("  stopped: {:?}", trace.stop);
+                }
                 unsafe { PROCESSES.remove(0) };
             }
         }
     }
-    system_reset(Shutdown, NoReason);
-    unreachable!()
+    sbi::shutdown()
 }
 
 /// Rust 异常处理函数，以异常方式关机。
+///
+/// 分配失败已由 [`alloc_error`] 报告并关机，不会到这里。先不经格式化打印一行，打印过程中再次 panic 时不再格式化，直接关机。
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    log::error!("{info}");
-    system_reset(Shutdown, SystemFailure);
-    loop {}
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static PANICKING: AtomicBool = AtomicBool::new(false);
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        rcore_console::emergency_print(b"\npanicked while panicking\n");
+        sbi::abort()
+    }
+    rcore_console::emergency_print(b"\nkernel panic\n");
+    match info.location() {
+        Some(loc) => log::error!(
+            "panicked at {}:{}:{}, satp = {:#x}",
+            loc.file(),
+            loc.line(),
+            loc.column(),
+            mmu::current_token()
+        ),
+        None => log::error!("panicked, satp = {:#x}", mmu::current_token()),
+    }
+    log::error!("satp: {}", mmu::current_satp());
+    log::error!("last trap: {}", trap::FaultInfo::current());
+    log::error!("{}", info.message());
+    sbi::abort()
+}
+
+/// 全局分配器分配失败时调用，不经格式化报告失败的大小后关机。
+///
+/// panic 处理要经过格式化和日志，内存耗尽时不一定可靠，因此在那之前先在栈上转换数字并直接输出。
+fn alloc_error(layout: Layout) {
+    let mut digits = [0u8; 20];
+    let mut i = digits.len();
+    let mut size = layout.size();
+    loop {
+        i -= 1;
+        digits[i] = b'0' + (size % 10) as u8;
+        size /= 10;
+        if size == 0 {
+            break;
+        }
+    }
+    rcore_console::emergency_print(
+        b"
+memory allocation of ",
+    );
+    rcore_console::emergency_print(&digits[i..]);
+    rcore_console::emergency_print(b" bytes failed\n");
+    sbi::abort()
 }
 
+/// 建立内核地址空间。
+///
+/// 除内核各段、动态内存和设备外，还映射 `extra` 中额外的区域，例如异界传送门或内核中的静态缓冲区。
 fn kernel_space(
     layout: linker::KernelLayout,
     memory: usize,
-    portal: usize,
-) -> AddressSpace<Sv39, Sv39Manager> {
-    let mut space = AddressSpace::<Sv39, Sv39Manager>::new();
+    extra: &[MappedRegion<Meta>],
+) -> AddressSpace<Meta, SvManager> {
+    let mut space = AddressSpace::<Meta, SvManager>::new();
     for region in layout.iter() {
-        log::info!("{region}");
         use linker::KernelRegionTitle::*;
+        let flags = kernel_flags().readable().valid();
         let flags = match region.title {
-            Text => "X_RV",
-            Rodata => "__RV",
-            Data | Boot => "_WRV",
+            Text => flags.executable(),
+            Rodata => flags,
+            Data | Boot => flags.writable(),
         };
-        let s = VAddr::<Sv39>::new(region.range.start);
-        let e = VAddr::<Sv39>::new(region.range.end);
+        let s = VAddr::<Meta>::new(region.range.start);
+        let e = VAddr::<Meta>::new(region.range.end);
         space.map_extern(
             s.floor()..e.ceil(),
-            PPN::new(s.floor().val()),
-            VmFlags::build_from_str(flags),
+            mmu::ppn_of(s.floor().base().val() as _),
+            flags.build(),
         )
     }
-    log::info!(
-        "(heap) ---> {:#10x}..{:#10x}",
-        layout.end(),
-        layout.start() + memory
-    );
-    let s = VAddr::<Sv39>::new(layout.end());
-    let e = VAddr::<Sv39>::new(layout.start() + memory);
+    let s = VAddr::<Meta>::new(layout.end());
+    let e = VAddr::<Meta>::new(layout.start() + memory);
     space.map_extern(
         s.floor()..e.ceil(),
-        PPN::new(s.floor().val()),
-        VmFlags::build_from_str("_WRV"),
-    );
-    space.map_extern(
-        PROTAL_TRANSIT..PROTAL_TRANSIT + 1,
-        PPN::new(portal >> Sv39::PAGE_BITS),
-        VmFlags::build_from_str("__G_XWRV"),
+        mmu::ppn_of(s.floor().base().val() as _),
+        kernel_flags().writable().readable().valid().build(),
     );
+    for &(ppn, count, flags) in MMIO {
+        space.map_extern(
+            VPN::new(ppn)..VPN::new(ppn + count),
+            PPN::new(ppn),
+            flags | VmFlags::build_from_str("G_____"),
+        );
+    }
+    for region in extra {
+        space.map_extern(region.vpn.clone(), region.ppn, region.flags);
+    }
+    mmu::log_kernel_layout(log::Level::Debug, &layout, memory, extra, space.root_ppn());
+    // 对照实际分配和预计的页表页数
+    let predicted = kernel_vm::predict_table_pages(&space.areas);
+    if space.table_pages() == predicted {
+        log::info!("page tables: {predicted} pages");
+    } else {
+        log::warn!(
+            "page tables: {} pages allocated, {predicted} predicted",
+            space.table_pages()
+        );
+    }
     println!();
-    unsafe { satp::set(satp::Mode::Sv39, 0, space.root_ppn().val()) };
+    unsafe { mmu::activate(&space, Asid::SHARED) };
+    debug_assert_eq!(mmu::current_token(), mmu::token(&space, Asid::SHARED));
+    log::info!("satp: {}", mmu::current_satp());
     space
 }
 
+/// 内核段和堆的基本页属性。启用 `global_kernel` 特性时带 G 位。
+///
+/// 只有叶子带 G 位。加载应用程序时，全部是全局映射的根页表项会共享给用户地址空间，见 [`AddressSpace::clone_global_entries`]。
+#[inline]
+const fn kernel_flags() -> FlagsBuilder<Meta> {
+    let flags = FlagsBuilder::new();
+    #[cfg(feature = "global_kernel")]
+    let flags = flags.global();
+    flags
+}
+
 /// 各种接口库的实现。
 mod impls {
-    use crate::PROCESSES;
-    use alloc::alloc::alloc_zeroed;
-    use core::{alloc::Layout, ptr::NonNull};
+    use crate::{mmu, Meta, PROCESSES};
+    use core::ptr::NonNull;
     use kernel_vm::{
-        page_table::{MmuMeta, Pte, Sv39, VAddr, VmFlags, PPN, VPN},
-        PageManager,
+        page_table::{MmuMeta, Pte, VAddr, VmFlags, PPN},
+        try_flags_from_raw, vpn_of_aligned, FlagsDisplay, PageManager, Unaligned,
     };
     use rcore_console::log;
     use syscall::*;
 
     #[repr(transparent)]
-    pub struct Sv39Manager(NonNull<Pte<Sv39>>);
+    pub struct SvManager(NonNull<Pte<Meta>>);
 
-    impl Sv39Manager {
-        const OWNED: VmFlags<Sv39> = unsafe { VmFlags::from_raw(1 << 8) };
+    impl SvManager {
+        const OWNED: VmFlags<Meta> = match try_flags_from_raw(1 << 8) {
+            Ok(flags) => flags,
+            Err(_) => panic!("invalid OWNED flag"),
+        };
 
         #[inline]
-        fn page_alloc<T>(count: usize) -> *mut T {
-            unsafe {
-                alloc_zeroed(Layout::from_size_align_unchecked(
-                    count << Sv39::PAGE_BITS,
-                    1 << Sv39::PAGE_BITS,
-                ))
-            }
-            .cast()
+        fn page_alloc<T>(count: usize) -> NonNull<T> {
+            kernel_alloc::allocate_pages(count)
+                .unwrap_or_else(|e| panic!("failed to allocate {count} pages: {e}"))
+                .cast()
         }
     }
 
-    impl PageManager<Sv39> for Sv39Manager {
+    impl PageManager<Meta> for SvManager {
         #[inline]
         fn new_root() -> Self {
-            Self(NonNull::new(Self::page_alloc(1)).unwrap())
+            Self(Self::page_alloc(1))
         }
 
         #[inline]
-        fn root_ppn(&self) -> PPN<Sv39> {
-            PPN::new(self.0.as_ptr() as usize >> Sv39::PAGE_BITS)
+        fn root_ptr(&self) -> NonNull<Pte<Meta>> {
+            self.0
         }
 
         #[inline]
-        fn root_ptr(&self) -> NonNull<Pte<Sv39>> {
-            self.0
+        fn p_to_v<T>(&self, ppn: PPN<Meta>) -> NonNull<T> {
+            let vaddr = mmu::virt_of(ppn.val() << Meta::PAGE_BITS);
+            unsafe { NonNull::new_unchecked(vaddr.as_mut_ptr()) }
         }
 
         #[inline]
-        fn p_to_v<T>(&self, ppn: PPN<Sv39>) -> NonNull<T> {
-            unsafe { NonNull::new_unchecked(VPN::<Sv39>::new(ppn.val()).base().as_mut_ptr()) }
+        fn v_to_p<T>(&self, ptr: NonNull<T>) -> PPN<Meta> {
+            // 这里的指针都来自页分配器，不对齐说明分配器出了错
+            let vpn = vpn_of_aligned::<Meta>(ptr.as_ptr().cast())
+                .unwrap_or_else(|Unaligned(addr)| panic!("page at {addr:#x} is not aligned"));
+            mmu::ppn_of(vpn.base().val() as _)
         }
 
         #[inline]
-        fn v_to_p<T>(&self, ptr: NonNull<T>) -> PPN<Sv39> {
-            PPN::new(VAddr::<Sv39>::new(ptr.as_ptr() as _).floor().val())
+        fn check_owned(&self, pte: Pte<Meta>) -> bool {
+            pte.flags().contains(Self::OWNED)
         }
 
         #[inline]
-        fn check_owned(&self, pte: Pte<Sv39>) -> bool {
-            pte.flags().contains(Self::OWNED)
+        fn allocate(&mut self, len: usize, flags: &mut VmFlags<Meta>) -> NonNull<u8> {
+            self.try_allocate(len, flags)
+                .unwrap_or_else(|| panic!("failed to allocate {len} pages"))
         }
 
         #[inline]
-        fn allocate(&mut self, len: usize, flags: &mut VmFlags<Sv39>) -> NonNull<u8> {
+        fn try_allocate(&mut self, len: usize, flags: &mut VmFlags<Meta>) -> Option<NonNull<u8>> {
+            let page = kernel_alloc::allocate_pages(len).ok()?;
             *flags |= Self::OWNED;
-            NonNull::new(Self::page_alloc(len)).unwrap()
+            Some(page)
         }
 
-        fn deallocate(&mut self, _pte: Pte<Sv39>, _len: usize) -> usize {
-            todo!()
+        #[inline]
+        fn deallocate(&mut self, pte: Pte<Meta>, len: usize) -> usize {
+            unsafe { kernel_alloc::deallocate_pages(self.p_to_v(pte.ppn()), len) };
+            len
         }
 
+        #[inline]
         fn drop_root(&mut self) {
-            todo!()
+            unsafe { kernel_alloc::deallocate_pages(self.0.cast(), 1) };
+        }
+    }
+
+    impl Drop for SvManager {
+        /// 地址空间不释放根页表，由页管理器随地址空间一起释放。
+        #[inline]
+        fn drop(&mut self) {
+            self.drop_root();
         }
     }
 
@@ -274,8 +564,17 @@ mod impls {
     impl rcore_console::Console for Console {
         #[inline]
         fn put_char(&self, c: u8) {
-            #[allow(deprecated)]
-            sbi_rt::legacy::console_putchar(c as _);
+            crate::sbi::console_putchar(c);
+        }
+
+        #[inline]
+        fn put_str(&self, s: &str) {
+            crate::sbi::console_write(s.as_bytes());
+        }
+
+        #[inline]
+        fn get_char(&self) -> Option<u8> {
+            crate::sbi::console_getchar()
         }
     }
 
@@ -285,12 +584,21 @@ mod impls {
         fn write(&self, caller: Caller, fd: usize, buf: usize, count: usize) -> isize {
             match fd {
                 STDOUT | STDDEBUG => {
-                    const READABLE: VmFlags<Sv39> = VmFlags::build_from_str("RV");
-                    if let Some(ptr) = unsafe { PROCESSES.get_mut(caller.entity) }
-                        .unwrap()
-                        .address_space
-                        .translate(VAddr::new(buf), READABLE)
-                    {
+                    const READABLE: VmFlags<Meta> = VmFlags::build_from_str("RV");
+                    let process = unsafe { PROCESSES.get_mut(caller.entity) }.unwrap();
+                    // 缓冲区可能在尚未分配的栈页上
+                    process.fault_in(buf, count);
+                    let space = &process.address_space;
+                    // 拒绝指向内核专用页的指针，例如没有 U 位的异界传送门
+                    if let Err(v) = space.assert_user_accessible(VAddr::new(buf), READABLE) {
+                        log::error!(
+                            "ptr not user readable: level {} [{}]",
+                            v.level,
+                            FlagsDisplay(v.flags)
+                        );
+                        return -1;
+                    }
+                    if let Some(ptr) = space.translate(VAddr::new(buf), READABLE) {
                         print!("{}", unsafe {
                             core::str::from_utf8_unchecked(core::slice::from_raw_parts(
                                 ptr.as_ptr(),
@@ -328,15 +636,14 @@ mod impls {
     impl Clock for SyscallContext {
         #[inline]
         fn clock_gettime(&self, caller: Caller, clock_id: ClockId, tp: usize) -> isize {
-            const WRITABLE: VmFlags<Sv39> = VmFlags::build_from_str("W_V");
+            const WRITABLE: VmFlags<Meta> = VmFlags::build_from_str("W_V");
             match clock_id {
                 ClockId::CLOCK_MONOTONIC => {
-                    if let Some(mut ptr) = unsafe { PROCESSES.get(caller.entity) }
-                        .unwrap()
-                        .address_space
-                        .translate(VAddr::new(tp), WRITABLE)
+                    let process = unsafe { PROCESSES.get_mut(caller.entity) }.unwrap();
+                    process.fault_in(tp, core::mem::size_of::<TimeSpec>());
+                    if let Some(mut ptr) = process.address_space.translate(VAddr::new(tp), WRITABLE)
                     {
-                        let time = riscv::register::time::read() * 10000 / 125;
+                        let time = crate::time::nanos() as usize;
                         *unsafe { ptr.as_mut() } = TimeSpec {
                             tv_sec: time / 1_000_000_000,
                             tv_nsec: time % 1_000_000_000,
@@ -0,0 +1,217 @@
+//! 地址空间的激活和地址转换缓存的刷新，以及内核地址空间布局的打印。
+//!
+//! 修改当前活跃的页表后，必须在访问受影响的虚地址之前调用 [`flush_tlb`] 或 [`flush_tlb_vaddr`]。
+//! `sfence.vma` 保证之前对页表的写入对之后的地址转换可见，因此刷新要放在写页表之后、访问之前。
+
+use crate::{asid::Asid, Meta, SvManager, MMIO, SATP_MODE};
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use kernel_vm::{
+    page_table::{MmuMeta, VAddr, PPN},
+    AddressSpace, MappedRegion,
+};
+use rcore_console::log;
+use riscv::register::satp;
+
+/// 内核虚地址与物理地址之差，由 [`init_offset`] 设置。
+static PHYS_VIRT_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// 由内核布局推算内核虚地址与物理地址之差。
+///
+/// 启用分页之前内核从物理地址运行，`layout` 中是内核实际所在的物理地址，
+/// 而内核的虚地址由链接脚本决定，见 [`linker::KERNEL_BASE`]，两者之差就是偏移。内核恒等映射时为 0。
+/// 必须在启用分页之前调用。物理地址和内核虚地址的换算都应通过 [`phys_of`] 和 [`virt_of`] 进行。
+pub fn init_offset(layout: &linker::KernelLayout) {
+    let offset = linker::KERNEL_BASE.wrapping_sub(layout.start());
+    PHYS_VIRT_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// 内核虚地址与物理地址之差。
+#[inline]
+pub fn phys_virt_offset() -> usize {
+    PHYS_VIRT_OFFSET.load(Ordering::Relaxed)
+}
+
+/// 计算内核虚地址对应的物理地址。
+#[inline]
+pub fn phys_of(ptr: *const u8) -> usize {
+    (ptr as usize).wrapping_sub(phys_virt_offset())
+}
+
+/// 计算物理地址在内核中的虚地址。
+#[inline]
+pub fn virt_of(paddr: usize) -> VAddr<Meta> {
+    VAddr::new(paddr.wrapping_add(phys_virt_offset()))
+}
+
+/// 计算内核虚地址所在的物理页号。
+#[inline]
+pub fn ppn_of(ptr: *const u8) -> PPN<Meta> {
+    PPN::new(phys_of(ptr) >> Meta::PAGE_BITS)
+}
+
+/// 以 `level` 级别的日志打印内核地址空间的布局。
+///
+/// 依次是内核各段、动态内存、设备、`extra` 中额外映射的区域和根页表的物理地址。
+pub fn log_kernel_layout(
+    level: log::Level,
+    layout: &linker::KernelLayout,
+    memory: usize,
+    extra: &[MappedRegion<Meta>],
+    root: PPN<Meta>,
+) {
+    for region in layout.iter() {
+        log::log!(level, "{region}");
+    }
+    log::log!(
+        level,
+        "(heap) ---> {:#10x}..{:#10x}",
+        layout.end(),
+        layout.start() + memory
+    );
+    for &(ppn, count, _) in MMIO {
+        log::log!(
+            level,
+            "(mmio) ---> {:#10x}..{:#10x}",
+            ppn << Meta::PAGE_BITS,
+            (ppn + count) << Meta::PAGE_BITS
+        );
+    }
+    for region in extra {
+        log::log!(
+            level,
+            "(extra) --> {:#10x}..{:#10x} -> {:#10x}",
+            region.vpn.start.val() << Meta::PAGE_BITS,
+            region.vpn.end.val() << Meta::PAGE_BITS,
+            region.ppn.val() << Meta::PAGE_BITS
+        );
+    }
+    log::log!(level, "(root) ---> {:#10x}", root.val() << Meta::PAGE_BITS);
+}
+
+/// 按 satp 的布局拼合分页模式、地址空间标识和根页表物理页号。
+#[inline]
+pub fn make_satp(asid: u16, root_ppn: PPN<Meta>) -> usize {
+    ((SATP_MODE as usize) << 60) | ((asid as usize) << 44) | root_ppn.val()
+}
+
+/// 将 satp 的值拆分为分页模式、地址空间标识和根页表物理页号，是 [`make_satp`] 的逆运算。
+#[inline]
+pub fn decode_satp(bits: usize) -> (usize, u16, PPN<Meta>) {
+    (
+        bits >> 60,
+        (bits >> 44) as u16,
+        PPN::new(bits & ((1 << 44) - 1)),
+    )
+}
+
+/// satp 中的分页模式。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SatpMode {
+    /// 不分页，此时地址空间标识和根页表物理页号没有意义。
+    Bare,
+    /// Sv39。
+    Sv39,
+    /// Sv48。
+    Sv48,
+    /// 其他或保留的模式，附带模式字段的值。
+    Other(usize),
+}
+
+/// 解码后的 satp。
+#[derive(Clone, Copy)]
+pub struct SatpInfo {
+    /// 分页模式。
+    pub mode: SatpMode,
+    /// 地址空间标识。
+    pub asid: u16,
+    /// 根页表的物理页号。
+    pub ppn: PPN<Meta>,
+}
+
+impl SatpInfo {
+    /// 解码 satp 的值。
+    #[inline]
+    pub fn decode(bits: usize) -> Self {
+        let (mode, asid, ppn) = decode_satp(bits);
+        let mode = match mode {
+            0 => SatpMode::Bare,
+            8 => SatpMode::Sv39,
+            9 => SatpMode::Sv48,
+            n => SatpMode::Other(n),
+        };
+        Self { mode, asid, ppn }
+    }
+}
+
+impl fmt::Display for SatpInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self.mode {
+            SatpMode::Bare => return write!(f, "Bare"),
+            SatpMode::Sv39 => "Sv39",
+            SatpMode::Sv48 => "Sv48",
+            SatpMode::Other(n) => return write!(f, "mode {n}"),
+        };
+        write!(
+            f,
+            "{mode}, asid = {}, root = {:#x}",
+            self.asid,
+            self.ppn.val()
+        )
+    }
+}
+
+/// 读取并解码当前的 satp。
+#[inline]
+pub fn current_satp() -> SatpInfo {
+    SatpInfo::decode(current_token())
+}
+
+/// 计算地址空间以 `asid` 为标识时写入 satp 的值。
+#[inline]
+pub fn token(space: &AddressSpace<Meta, SvManager>, asid: Asid) -> usize {
+    make_satp(asid.val(), space.root_ppn())
+}
+
+/// 读取当前 satp 的值。
+#[inline]
+pub fn current_token() -> usize {
+    satp::read().bits()
+}
+
+/// 以 `asid` 为标识切换到 `space`，并刷新这个标识的地址转换缓存。
+///
+/// `asid` 是 [`Asid::SHARED`] 时与其他地址空间无法区分，全部刷新。
+///
+/// # Safety
+///
+/// 调用者需要保证 `space` 映射了当前正在执行的代码和栈，并在活跃期间保持有效。
+#[inline]
+pub unsafe fn activate(space: &AddressSpace<Meta, SvManager>, asid: Asid) {
+    satp::set(SATP_MODE, asid.val() as _, space.root_ppn().val());
+    if asid == Asid::SHARED {
+        flush_tlb();
+    } else {
+        flush_tlb_asid(asid);
+    }
+}
+
+/// 刷新全部地址转换缓存。
+#[inline]
+pub fn flush_tlb() {
+    unsafe { core::arch::asm!("sfence.vma") };
+}
+
+/// 刷新 `vaddr` 所在页的地址转换缓存。
+#[inline]
+pub fn flush_tlb_vaddr(vaddr: usize) {
+    unsafe { core::arch::asm!("sfence.vma {0}, zero", in(reg) vaddr) };
+}
+
+/// 刷新 `asid` 的全部地址转换缓存，不影响全局页。
+#[inline]
+pub fn flush_tlb_asid(asid: Asid) {
+    unsafe { core::arch::asm!("sfence.vma zero, {0}", in(reg) asid.val() as usize) };
+}
@@ -1,87 +1,488 @@
-﻿use crate::Sv39Manager;
-use alloc::alloc::alloc_zeroed;
-use core::{alloc::Layout, str::FromStr};
+﻿use crate::{
+    asid::{self, Asid},
+    mmu, Meta, SvManager,
+};
+use alloc::vec::Vec;
+use core::{fmt, ops::Range};
 use kernel_context::{foreign::ForeignContext, LocalContext};
 use kernel_vm::{
-    page_table::{MmuMeta, Sv39, VAddr, VmFlags, PPN, VPN},
-    AddressSpace,
+    page_table::{MmuMeta, VAddr, VmFlags, VPN},
+    AddressSpace, FlagsDisplay, MapError,
 };
 use rcore_console::log;
 use xmas_elf::{
     header::{self, HeaderPt2, Machine},
-    program, ElfFile,
+    program,
+    sections::ShType,
+    ElfFile,
 };
 
-/// 进程。
-pub struct Process {
-    pub context: ForeignContext,
-    pub address_space: AddressSpace<Sv39, Sv39Manager>,
+/// 加载应用程序失败的原因。
+#[derive(Debug)]
+pub enum LoadError {
+    /// ELF 头或程序头无法解析，或不是 64 位 ELF。
+    Malformed,
+    /// 不是可执行文件。
+    NotExecutable,
+    /// 不是 RISC-V 架构。
+    WrongMachine,
+    /// 段在文件中的范围超出了 ELF 数据。
+    Truncated,
+    /// LOAD 段的布局不合法。
+    BadSegment(SegmentError),
+    /// 段覆盖了用户栈的位置。
+    StackOverlap,
+    /// 内存不足。
+    OutOfMemory,
+    /// 建立映射失败。
+    Map(MapError),
 }
 
-impl Process {
-    pub fn new(elf: ElfFile) -> Option<Self> {
-        let entry = match elf.header.pt2 {
-            HeaderPt2::Header64(pt2)
-                if pt2.type_.as_type() == header::Type::Executable
-                    && pt2.machine.as_machine() == Machine::RISC_V =>
-            {
-                pt2.entry_point as usize
-            }
-            _ => None?,
-        };
+impl From<MapError> for LoadError {
+    #[inline]
+    fn from(e: MapError) -> Self {
+        match e {
+            MapError::OutOfMemory => Self::OutOfMemory,
+            e => Self::Map(e),
+        }
+    }
+}
 
-        const PAGE_SIZE: usize = 1 << Sv39::PAGE_BITS;
-        const PAGE_MASK: usize = PAGE_SIZE - 1;
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "malformed ELF"),
+            Self::NotExecutable => write!(f, "not an executable"),
+            Self::WrongMachine => write!(f, "not a RISC-V ELF"),
+            Self::Truncated => write!(f, "segment data out of file"),
+            Self::BadSegment(e) => write!(f, "program header {}: {}", e.index, e.kind),
+            Self::StackOverlap => write!(f, "segments overlap the user stack"),
+            Self::OutOfMemory => write!(f, "out of memory"),
+            Self::Map(e) => write!(f, "failed to map: {e:?}"),
+        }
+    }
+}
 
-        let mut address_space = AddressSpace::new();
-        for program in elf.program_iter() {
-            if !matches!(program.get_type(), Ok(program::Type::Load)) {
-                continue;
+/// LOAD 段布局不合法。
+#[derive(Debug)]
+pub struct SegmentError {
+    /// 出错的段在程序头表中的序号。
+    pub index: usize,
+    /// 出错的原因。
+    pub kind: SegmentErrorKind,
+}
+
+/// LOAD 段布局不合法的原因。
+#[derive(Debug)]
+pub enum SegmentErrorKind {
+    /// 没有按虚地址升序排列。
+    Unsorted,
+    /// 与前一个段重叠。
+    Overlapping,
+    /// 文件中的大小超过了内存中的大小。
+    FileLargerThanMemory,
+    /// 文件偏移和虚地址的页内偏移不同，无法按页映射。
+    Misaligned,
+}
+
+impl fmt::Display for SegmentErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsorted => write!(f, "segments not sorted by vaddr"),
+            Self::Overlapping => write!(f, "overlaps the previous segment"),
+            Self::FileLargerThanMemory => write!(f, "file size exceeds memory size"),
+            Self::Misaligned => write!(f, "file offset and vaddr differ within a page"),
+        }
+    }
+}
+
+/// 检查 LOAD 段按虚地址升序排列、互不重叠，文件中的大小不超过内存中的大小，且文件偏移与虚地址的页内偏移相同。
+pub fn validate_segments(elf: &ElfFile) -> Result<(), SegmentError> {
+    const PAGE_MASK: u64 = (1 << Meta::PAGE_BITS) - 1;
+    let mut last: Option<(usize, usize)> = None;
+    for (index, program) in elf.program_iter().enumerate() {
+        if !matches!(program.get_type(), Ok(program::Type::Load)) {
+            continue;
+        }
+        let error = |kind| SegmentError { index, kind };
+        if program.file_size() > program.mem_size() {
+            return Err(error(SegmentErrorKind::FileLargerThanMemory));
+        }
+        if program.offset() & PAGE_MASK != program.virtual_addr() & PAGE_MASK {
+            return Err(error(SegmentErrorKind::Misaligned));
+        }
+        let start = program.virtual_addr() as usize;
+        let end = start.saturating_add(program.mem_size() as usize);
+        match last {
+            Some((last_start, _)) if start < last_start => {
+                return Err(error(SegmentErrorKind::Unsorted))
+            }
+            Some((_, last_end)) if start < last_end => {
+                return Err(error(SegmentErrorKind::Overlapping))
             }
+            _ => {}
+        }
+        last = Some((start, end));
+    }
+    Ok(())
+}
 
-            let off_file = program.offset() as usize;
-            let len_file = program.file_size() as usize;
-            let off_mem = program.virtual_addr() as usize;
-            let end_mem = off_mem + program.mem_size() as usize;
-            assert_eq!(off_file & PAGE_MASK, off_mem & PAGE_MASK);
+/// ELF 文件概要。
+pub struct ElfSummary {
+    /// 入口地址。
+    pub entry: usize,
+    /// 目标架构。
+    pub machine: Machine,
+    /// 每个 LOAD 段的虚地址范围和权限。
+    pub segments: Vec<(Range<usize>, program::Flags)>,
+}
 
-            let mut flags: [u8; 5] = *b"U___V";
-            if program.flags().is_execute() {
-                flags[1] = b'X';
+/// 按名字查找 ELF 节，返回节在文件中的内容。
+///
+/// 没有节头表、节头表的大小溢出、节头或节内容超出文件、找不到这个节，或者节在文件中不占空间（如 `.bss`）时返回 `None`。
+pub fn elf_section<'a>(elf: &ElfFile<'a>, name: &str) -> Option<&'a [u8]> {
+    let pt2 = &elf.header.pt2;
+    let len = elf.input.len() as u64;
+    let count = pt2.sh_count() as u64;
+    let table_end = count
+        .checked_mul(pt2.sh_entry_size() as u64)
+        .and_then(|size| pt2.sh_offset().checked_add(size))?;
+    if count == 0 || table_end > len || pt2.sh_str_index() as u64 >= count {
+        return None;
+    }
+    let in_file = |offset: u64, size: u64| offset.checked_add(size).is_some_and(|end| end <= len);
+    // 节名在名字表中，先确定名字表本身在文件内
+    let names = elf.section_header(pt2.sh_str_index()).ok()?;
+    if !in_file(names.offset(), names.size()) {
+        return None;
+    }
+    let names = &elf.input[names.offset() as usize..][..names.size() as usize];
+    (0..pt2.sh_count())
+        .filter_map(|i| elf.section_header(i).ok())
+        .find(|section| {
+            let start = section.name() as usize;
+            start < names.len() && names[start..].split(|&b| b == 0).next() == Some(name.as_bytes())
+        })
+        .filter(|section| !matches!(section.get_type(), Ok(ShType::NoBits)))
+        .filter(|section| in_file(section.offset(), section.size()))
+        .map(|section| &elf.input[section.offset() as usize..][..section.size() as usize])
+}
+
+/// 检查 ELF 文件并生成概要，不映射任何内容。
+pub fn inspect_elf(elf: &ElfFile) -> Result<ElfSummary, LoadError> {
+    let HeaderPt2::Header64(pt2) = elf.header.pt2 else {
+        return Err(LoadError::Malformed);
+    };
+    if pt2.type_.as_type() != header::Type::Executable {
+        return Err(LoadError::NotExecutable);
+    }
+    let machine = pt2.machine.as_machine();
+    if machine != Machine::RISC_V {
+        return Err(LoadError::WrongMachine);
+    }
+    validate_segments(elf).map_err(LoadError::BadSegment)?;
+    let mut segments = Vec::new();
+    for program in elf.program_iter() {
+        match program.get_type() {
+            Ok(program::Type::Load) => {
+                let start = program.virtual_addr() as usize;
+                let end = start
+                    .checked_add(program.mem_size() as usize)
+                    .ok_or(LoadError::Malformed)?;
+                segments.push((start..end, program.flags()));
             }
-            if program.flags().is_write() {
-                flags[2] = b'W';
+            Ok(_) => {}
+            Err(_) => return Err(LoadError::Malformed),
+        }
+    }
+    Ok(ElfSummary {
+        entry: pt2.entry_point as usize,
+        machine,
+        segments,
+    })
+}
+
+/// 用户栈顶所在的虚页号。
+const STACK_TOP: usize = 1 << 26;
+
+/// 用户栈顶之下预留的页。
+const EXTRA_PAGES: ExtraPages = ExtraPages {
+    stack_pages: 2,
+    guard_pages: 1,
+    trampoline_pages: 0,
+};
+
+/// 进程地址空间中在段之外预留的页。
+///
+/// 从栈顶向下依次是栈页和保护页。保护页不映射，栈溢出时触发缺页异常。
+/// 本章的异界传送门通过根页表的最后一项共享，不需要为每个进程另外分配，因此 `trampoline_pages` 为 0。
+#[derive(Clone, Copy)]
+pub struct ExtraPages {
+    /// 用户栈页数。
+    pub stack_pages: usize,
+    /// 栈之下的保护页数。
+    pub guard_pages: usize,
+    /// 每个进程另外分配的传送门页数。
+    pub trampoline_pages: usize,
+}
+
+impl ExtraPages {
+    /// 栈顶之下预留的总页数。
+    #[inline]
+    pub const fn total(&self) -> usize {
+        self.stack_pages + self.guard_pages
+    }
+
+    /// 需要分配物理页的页数。保护页不映射，不占用物理页。
+    #[inline]
+    pub const fn allocated(&self) -> usize {
+        self.stack_pages + self.trampoline_pages
+    }
+}
+
+/// 解析第 `i` 个应用程序的 ELF，失败时记录 `xmas_elf` 给出的原因并返回 `None`。
+///
+/// 一个应用程序损坏时跳过它，不影响其他应用程序的加载。
+pub fn parse_app(i: usize, elf: &[u8]) -> Option<ElfFile> {
+    match ElfFile::new(elf) {
+        Ok(elf) => Some(elf),
+        Err(e) => {
+            log::error!("app[{i}] is not a valid ELF: {e}");
+            None
+        }
+    }
+}
+
+/// 一个应用程序的加载计划。
+///
+/// 保存解析和检查的结果，加载时不再重复。
+pub struct AppPlan {
+    /// 应用程序的序号。
+    pub index: usize,
+    /// 解析过的 ELF。
+    pub elf: ElfFile<'static>,
+    /// ELF 文件概要，入口地址也在其中。
+    pub summary: ElfSummary,
+    /// 加载需要的物理页数，见 [`ElfSummary::pages`]。
+    pub pages: usize,
+}
+
+/// 解析所有应用程序，统计各自的入口和需要的物理页数。
+///
+/// 无法解析或检查不通过的应用程序记录原因后跳过，不出现在结果中。
+/// 只遍历一次 ELF，调度器可以据此决定接纳哪些应用程序。
+pub fn survey_apps(apps: &'static linker::AppMeta) -> Vec<AppPlan> {
+    apps.iter()
+        .enumerate()
+        .filter_map(|(index, elf)| {
+            let elf = parse_app(index, elf)?;
+            match inspect_elf(&elf) {
+                Ok(summary) => Some(AppPlan {
+                    index,
+                    elf,
+                    pages: summary.pages(),
+                    summary,
+                }),
+                Err(e) => {
+                    log::error!("app[{index}] failed to load: {e}");
+                    None
+                }
             }
-            if program.flags().is_read() {
-                flags[3] = b'R';
+        })
+        .collect()
+}
+
+impl ElfSummary {
+    /// 统计加载需要的物理页数，包括段、用户栈和传送门页，不包括页表。
+    ///
+    /// 按段的虚地址原样加载，见 [`ElfSummary::pages_at`]。
+    #[inline]
+    pub fn pages(&self) -> usize {
+        self.pages_at(0)
+    }
+
+    /// 统计加载到偏移 `load_bias` 处需要的物理页数，包括段、用户栈和传送门页，不包括页表。
+    ///
+    /// 每个段的虚地址加上 `load_bias` 后再按页取整，因此段不按页对齐时页数可能随偏移变化。
+    /// 用于段地址从 0 起算、运行时才确定加载基址的位置无关程序。
+    pub fn pages_at(&self, load_bias: usize) -> usize {
+        let segments = self
+            .segments
+            .iter()
+            .map(|(range, _)| {
+                let start = range.start.saturating_add(load_bias);
+                let end = range.end.saturating_add(load_bias);
+                let start = VAddr::<Meta>::new(start).floor().val();
+                let end = VAddr::<Meta>::new(end).ceil().val();
+                end - start
+            })
+            .sum::<usize>();
+        segments + EXTRA_PAGES.allocated()
+    }
+}
+
+/// 进程。
+pub struct Process {
+    pub context: ForeignContext,
+    pub address_space: AddressSpace<Meta, SvManager>,
+    asid: Asid,
+}
+
+impl Process {
+    /// 如果缺页的地址 `addr` 在按需分配的栈页上，分配并映射这一页，返回是否处理了这次缺页。
+    pub fn handle_lazy_fault(&mut self, addr: usize) -> bool {
+        let vpn = VAddr::<Meta>::new(addr).floor();
+        let filled = self
+            .address_space
+            .fill_lazy(vpn, VmFlags::build_from_str("U_WRV"));
+        if filled {
+            mmu::flush_tlb_asid(self.asid);
+        }
+        filled
+    }
+
+    /// 分配从 `addr` 开始 `len` 字节内按需分配的页，系统调用访问用户缓冲区之前调用。
+    ///
+    /// 遇到既未映射也不能按需分配的页就停止，之后的翻译照常检查并拒绝它。
+    pub fn fault_in(&mut self, addr: usize, len: usize) {
+        const MASK: usize = (1 << Meta::PAGE_BITS) - 1;
+        let pages =
+            ((addr & MASK).saturating_add(len).saturating_add(MASK) >> Meta::PAGE_BITS).max(1);
+        let start = VAddr::<Meta>::new(addr).floor();
+        for vpn in kernel_vm::vpn_range(start, pages) {
+            let vaddr = vpn.base();
+            if self.address_space.translate_phys(vaddr).is_none()
+                && !self.handle_lazy_fault(vaddr.val())
+            {
+                break;
             }
-            address_space.map(
-                VAddr::new(off_mem).floor()..VAddr::new(end_mem).ceil(),
-                &elf.input[off_file..][..len_file],
-                off_mem & PAGE_MASK,
-                VmFlags::from_str(unsafe { core::str::from_utf8_unchecked(&flags) }).unwrap(),
-            );
         }
-        let stack = unsafe {
-            alloc_zeroed(Layout::from_size_align_unchecked(
-                2 << Sv39::PAGE_BITS,
-                1 << Sv39::PAGE_BITS,
-            ))
-        };
-        address_space.map_extern(
-            VPN::new((1 << 26) - 2)..VPN::new(1 << 26),
-            PPN::new(stack as usize >> Sv39::PAGE_BITS),
-            VmFlags::build_from_str("U_WRV"),
+    }
+
+    /// 按加载计划建立进程，使用计划中已经检查过的 ELF 和概要。
+    pub fn new(plan: &AppPlan) -> Result<Self, LoadError> {
+        let AppPlan { elf, summary, .. } = plan;
+        log::info!(
+            "process entry = {:#x}, machine = {:?}",
+            summary.entry,
+            summary.machine
         );
+        if let Some(symtab) = elf_section(elf, ".symtab") {
+            log::debug!("  .symtab: {} bytes", symtab.len());
+        }
+        for (range, flags) in &summary.segments {
+            log::info!(
+                "  {:#x}..{:#x} [{}]",
+                range.start,
+                range.end,
+                FlagsDisplay(user_flags(*flags))
+            );
+        }
 
-        log::info!("process entry = {:#x}", entry);
+        let mut address_space = AddressSpace::new();
+        let sp = match map_image(elf, &mut address_space) {
+            Ok(sp) => sp,
+            Err(e) => {
+                // 归还已经映射的页和页表，根页表随页管理器释放
+                address_space.release();
+                return Err(e);
+            }
+        };
 
-        let mut context = LocalContext::user(entry);
-        let satp = (8 << 60) | address_space.root_ppn().val();
-        *context.sp_mut() = 1 << 38;
-        Some(Self {
+        let mut context = LocalContext::user(summary.entry);
+        let asid = asid::alloc();
+        let satp = mmu::token(&address_space, asid);
+        *context.sp_mut() = sp;
+        Ok(Self {
             context: ForeignContext { context, satp },
             address_space,
+            asid,
         })
     }
 }
+
+impl Drop for Process {
+    #[inline]
+    fn drop(&mut self) {
+        self.address_space.release();
+        asid::dealloc(self.asid);
+    }
+}
+
+/// 将 ELF 的可加载段和用户栈映射到 `address_space`，返回初始的栈指针。
+///
+/// 失败时已经映射的段留在地址空间中，由调用者释放。
+fn map_image(
+    elf: &ElfFile,
+    address_space: &mut AddressSpace<Meta, SvManager>,
+) -> Result<usize, LoadError> {
+    const PAGE_SIZE: usize = 1 << Meta::PAGE_BITS;
+    const PAGE_MASK: usize = PAGE_SIZE - 1;
+
+    for program in elf.program_iter() {
+        if !matches!(program.get_type(), Ok(program::Type::Load)) {
+            continue;
+        }
+
+        // 文件偏移和虚地址的页内偏移相同，已由 `validate_segments` 检查
+        let off_file = program.offset() as usize;
+        let len_file = program.file_size() as usize;
+        let off_mem = program.virtual_addr() as usize;
+        let end_mem = off_mem + program.mem_size() as usize;
+
+        let data = off_file
+            .checked_add(len_file)
+            .and_then(|end_file| elf.input.get(off_file..end_file))
+            .ok_or(LoadError::Truncated)?;
+        address_space.try_map(
+            VAddr::new(off_mem).floor()..VAddr::new(end_mem).ceil(),
+            data,
+            off_mem & PAGE_MASK,
+            user_flags(program.flags()),
+        )?;
+    }
+    map_user_stack(address_space, EXTRA_PAGES)
+}
+
+/// 在栈顶之下映射用户栈，返回初始的栈指针。
+///
+/// 栈顶一页立即映射，其余栈页预留为按需分配。栈页之下留出 `pages.guard_pages` 个不映射的保护页。
+/// 栈页由地址空间分配，随 [`AddressSpace::release`] 归还。失败时撤销这里建立的映射。
+pub fn map_user_stack(
+    address_space: &mut AddressSpace<Meta, SvManager>,
+    pages: ExtraPages,
+) -> Result<usize, LoadError> {
+    // 用户栈和保护页不能覆盖已经映射的段
+    let bottom = STACK_TOP - pages.total();
+    if !address_space.is_range_free(VPN::new(bottom), pages.total()) {
+        return Err(LoadError::StackOverlap);
+    }
+    // 只有栈顶一页立即分配，其余栈页用到时再分配，见 `Process::handle_lazy_fault`
+    let top = VPN::new(STACK_TOP - 1)..VPN::new(STACK_TOP);
+    address_space.try_map(top.clone(), &[], 0, VmFlags::build_from_str("U_WRV"))?;
+    if pages.stack_pages > 1 {
+        let lazy = VPN::new(bottom + pages.guard_pages)..top.start;
+        if let Err(e) = address_space.reserve_lazy(lazy) {
+            address_space.unmap(top);
+            return Err(e.into());
+        }
+    }
+    Ok(STACK_TOP << Meta::PAGE_BITS)
+}
+
+/// 根据 ELF 段的权限生成用户页属性。
+///
+/// 总是设置 `U` 和 `V` 位。没有任何权限的段映射为有效但不可访问的页，以捕获意外的访问。
+fn user_flags(flags: program::Flags) -> VmFlags<Meta> {
+    let mut ans = VmFlags::build_from_str("U___V");
+    if flags.is_execute() {
+        ans |= VmFlags::build_from_str("X___");
+    }
+    if flags.is_write() {
+        ans |= VmFlags::build_from_str("W__");
+    }
+    if flags.is_read() {
+        ans |= VmFlags::build_from_str("R_");
+    }
+    ans
+}
@@ -0,0 +1,105 @@
+//! SBI 调用的简单封装。
+//!
+//! 依赖的 sbi-rt 0.0.2 及其 sbi-spec 0.0.4 早于 SBI 2.0，没有 Debug Console 扩展的接口，
+//! 探测扩展的接口也只接受其中定义过的扩展，因此探测和使用 Debug Console 扩展时自行发起调用。
+//! 升级到提供 `sbi_rt::console_write` 的版本后，应换成 `sbi_rt::probe_extension` 和 `sbi_rt::console_write`。
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Debug Console 扩展的编号。
+const EID_DBCN: usize = 0x4442_434e;
+
+/// SBI 是否支持 Debug Console 扩展。
+static DBCN: AtomicBool = AtomicBool::new(false);
+
+/// 探测 SBI 扩展。在使用控制台之前调用。
+pub fn init() {
+    // Base 扩展的 `sbi_probe_extension`，不支持时返回 0
+    let (_, available) = sbi_call(0x10, 3, EID_DBCN, 0, 0);
+    DBCN.store(available != 0, Ordering::Relaxed);
+    // 本章不使用时钟中断，推迟固件可能设置的下一次时钟中断
+    set_timer(u64::MAX);
+}
+
+/// 正常关机。关机前输出控制台缓冲中剩余的内容。
+#[inline]
+pub fn shutdown() -> ! {
+    rcore_console::flush();
+    sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::NoReason);
+    unreachable!()
+}
+
+/// 以异常方式关机。
+///
+/// 在异常处理函数中使用，因此不会再次引发异常。关机前尽量输出控制台缓冲中剩余的内容。
+#[inline]
+pub fn abort() -> ! {
+    rcore_console::flush();
+    sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::SystemFailure);
+    loop {}
+}
+
+/// 设置在 `time` 寄存器达到 `ticks` 时触发时钟中断。
+#[inline]
+pub fn set_timer(ticks: u64) {
+    sbi_rt::set_timer(ticks);
+}
+
+/// 向控制台输出一个字符。
+#[inline]
+pub fn console_putchar(c: u8) {
+    #[allow(deprecated)]
+    sbi_rt::legacy::console_putchar(c as _);
+}
+
+/// 向控制台输出一段字节。
+///
+/// 支持 Debug Console 扩展时整段输出，否则逐字符输出。
+pub fn console_write(bytes: &[u8]) {
+    if !DBCN.load(Ordering::Relaxed) {
+        bytes.iter().for_each(|&c| console_putchar(c));
+        return;
+    }
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        // 参数是物理地址，高位在 rv64 上为 0
+        let base = crate::mmu::phys_of(rest.as_ptr());
+        // 没有写出任何字节时也逐字符输出，否则会一直重试
+        match sbi_call(EID_DBCN, 0, rest.len(), base, 0) {
+            (0, written) if written != 0 => rest = &rest[written.min(rest.len())..],
+            _ => {
+                rest.iter().for_each(|&c| console_putchar(c));
+                return;
+            }
+        }
+    }
+}
+
+/// 从控制台读取一个字符，没有输入时返回 `None`。
+#[inline]
+pub fn console_getchar() -> Option<u8> {
+    #[allow(deprecated)]
+    match sbi_rt::legacy::console_getchar() {
+        usize::MAX => None,
+        c => Some(c as _),
+    }
+}
+
+/// 发起 SBI 调用，返回 `(error, value)`。
+///
+/// 只用于 sbi-rt 0.0.2 没有封装的调用，见模块文档。
+#[inline(always)]
+fn sbi_call(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> (usize, usize) {
+    let (error, value);
+    unsafe {
+        core::arch::asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") fid,
+            in("a7") eid,
+        )
+    };
+    (error, value)
+}
@@ -0,0 +1,58 @@
+//! 时钟和启动阶段计时。
+//!
+//! 未启用 `profiling` 特性时 [`Stopwatch`] 不读寄存器也不打印，编译后没有开销。
+
+/// `time` 寄存器的频率，默认为 QEMU virt 的 12.5 MHz，可由 `TIMEBASE_FREQ` 指定。
+pub const TIMEBASE_FREQ: u64 = linker::frequency(option_env!("TIMEBASE_FREQ"), 12_500_000) as _;
+
+/// 读取当前时钟周期数。
+#[inline]
+pub fn read() -> u64 {
+    riscv::register::time::read64()
+}
+
+/// 读取当前时间，单位纳秒。
+#[inline]
+pub fn nanos() -> u64 {
+    (read() as u128 * 1_000_000_000 / TIMEBASE_FREQ as u128) as _
+}
+
+/// 检查时钟单调递增。检查失败时 panic。
+#[cfg(feature = "self-test")]
+pub fn self_test() {
+    let (t0, n0) = (read(), nanos());
+    while read() == t0 {
+        core::hint::spin_loop();
+    }
+    let (t1, n1) = (read(), nanos());
+    assert!(t0 < t1, "time went backwards: {t0} -> {t1}");
+    assert!(n0 <= n1, "nanos went backwards: {n0} -> {n1}");
+}
+
+/// 读取 `time` 寄存器的秒表，每次 [`lap`](Stopwatch::lap) 打印距上一次的时钟周期数。
+pub struct Stopwatch {
+    #[cfg(feature = "profiling")]
+    last: u64,
+}
+
+impl Stopwatch {
+    /// 开始计时。
+    #[inline]
+    pub fn start() -> Self {
+        Self {
+            #[cfg(feature = "profiling")]
+            last: read(),
+        }
+    }
+
+    /// 打印距上一次计时经过的时钟周期数，并重新计时。
+    #[inline]
+    pub fn lap(&mut self, _label: &str) {
+        #[cfg(feature = "profiling")]
+        {
+            let now = read();
+            rcore_console::log::info!("[profile] {_label}: {} cycles", now - self.last);
+            self.last = now;
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! 陷入原因的解码和异界传送门的地址。
+
+use crate::Meta;
+use core::fmt;
+use kernel_vm::page_table::MmuMeta;
+
+/// 异界传送门所在的虚地址，是地址空间最高的一页，按符号扩展。
+///
+/// 传送门页在内核地址空间和每个用户地址空间中映射到同一个虚地址和同一个物理页：
+/// 内核地址空间建立映射，加载进程时把根页表中对应的页表项复制过去。
+/// 因此传送门中的代码在切换 satp 前后地址不变，不需要在两个地址之间换算。
+pub const TRAMPOLINE_VADDR: usize = usize::MAX << Meta::PAGE_BITS;
+
+/// 计算传送门页中的代码在 [`TRAMPOLINE_VADDR`] 处的虚地址。
+///
+/// `f` 是代码在传送门页的物理页中的地址，内核动态内存恒等映射，也就是它在内核中分配时得到的地址。
+/// 传送门页按页对齐，因此只需保留页内偏移。
+#[inline]
+pub fn trampoline_offset(f: fn()) -> usize {
+    TRAMPOLINE_VADDR + (f as usize & ((1 << Meta::PAGE_BITS) - 1))
+}
+
+/// 陷入的种类。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaultKind {
+    /// 指令地址未对齐。
+    InstructionMisaligned,
+    /// 指令访问错误。
+    InstructionAccessFault,
+    /// 非法指令。
+    IllegalInstruction,
+    /// 断点。
+    Breakpoint,
+    /// 读地址未对齐。
+    LoadMisaligned,
+    /// 读访问错误。
+    LoadAccessFault,
+    /// 写地址未对齐。
+    StoreMisaligned,
+    /// 写访问错误。
+    StoreAccessFault,
+    /// 来自用户态的系统调用。
+    UserEnvCall,
+    /// 来自内核态的系统调用。
+    SupervisorEnvCall,
+    /// 取指缺页。
+    InstructionPageFault,
+    /// 读缺页。
+    LoadPageFault,
+    /// 写缺页。
+    StorePageFault,
+    /// 中断，附带中断号。
+    Interrupt(usize),
+    /// 未定义的异常，附带异常号。
+    Unknown(usize),
+}
+
+/// 解码后的陷入原因。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FaultInfo {
+    /// 陷入的种类。
+    pub kind: FaultKind,
+    /// `stval` 的值：访存和取指异常时是出错的地址，非法指令时是指令本身，其他时候通常为 0。
+    pub stval: usize,
+}
+
+impl FaultInfo {
+    /// 解码 `scause` 和 `stval` 的值。
+    pub fn decode(scause: usize, stval: usize) -> Self {
+        use FaultKind::*;
+        const INTERRUPT: usize = 1 << (usize::BITS - 1);
+        let code = scause & !INTERRUPT;
+        let kind = if scause & INTERRUPT != 0 {
+            Interrupt(code)
+        } else {
+            match code {
+                0 => InstructionMisaligned,
+                1 => InstructionAccessFault,
+                2 => IllegalInstruction,
+                3 => Breakpoint,
+                4 => LoadMisaligned,
+                5 => LoadAccessFault,
+                6 => StoreMisaligned,
+                7 => StoreAccessFault,
+                8 => UserEnvCall,
+                9 => SupervisorEnvCall,
+                12 => InstructionPageFault,
+                13 => LoadPageFault,
+                15 => StorePageFault,
+                n => Unknown(n),
+            }
+        };
+        Self { kind, stval }
+    }
+
+    /// 读取并解码当前的 `scause` 和 `stval`。
+    #[inline]
+    pub fn current() -> Self {
+        use riscv::register::{scause, stval};
+        Self::decode(scause::read().bits(), stval::read())
+    }
+
+    /// 如果是访存或取指异常，返回出错的地址。
+    pub fn fault_addr(&self) -> Option<usize> {
+        use FaultKind::*;
+        match self.kind {
+            InstructionMisaligned
+            | InstructionAccessFault
+            | LoadMisaligned
+            | LoadAccessFault
+            | StoreMisaligned
+            | StoreAccessFault
+            | InstructionPageFault
+            | LoadPageFault
+            | StorePageFault => Some(self.stval),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FaultInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            FaultKind::Interrupt(n) => write!(f, "interrupt {n}"),
+            FaultKind::Unknown(n) => write!(f, "unknown exception {n}, stval = {:#x}", self.stval),
+            FaultKind::IllegalInstruction => {
+                write!(f, "illegal instruction {:#010x}", self.stval)
+            }
+            kind => match self.fault_addr() {
+                Some(addr) => write!(f, "{kind:?} at {addr:#x}"),
+                None => write!(f, "{kind:?}"),
+            },
+        }
+    }
+}
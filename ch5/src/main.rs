@@ -13,7 +13,7 @@ extern crate rcore_console;
 extern crate alloc;
 
 use alloc::{alloc::alloc, collections::BTreeMap};
-use core::{alloc::Layout, ffi::CStr, mem::MaybeUninit};
+use core::{alloc::Layout, mem::MaybeUninit};
 use impls::{Console, Sv39Manager, SyscallContext};
 use kernel_context::foreign::MultislotPortal;
 use kernel_vm::{
@@ -45,16 +45,7 @@ static APPS: Lazy<BTreeMap<&'static str, &'static [u8]>> = Lazy::new(|| {
     extern "C" {
         static app_names: u8;
     }
-    unsafe {
-        linker::AppMeta::locate()
-            .iter()
-            .scan(&app_names as *const _ as usize, |addr, data| {
-                let name = CStr::from_ptr(*addr as _).to_str().unwrap();
-                *addr += name.as_bytes().len() + 1;
-                Some((name, data))
-            })
-    }
-    .collect()
+    unsafe { linker::AppMeta::locate().iter_named(&app_names) }.collect()
 });
 
 extern "C" fn rust_main() -> ! {
@@ -6,8 +6,9 @@
 use core::{
     fmt::{self, Write},
     str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
 };
-use spin::Once;
+use spin::{Mutex, Once};
 
 /// 向用户提供 `log`。
 pub extern crate log;
@@ -17,6 +18,14 @@ pub trait Console: Sync {
     /// 向控制台放置一个字符。
     fn put_char(&self, c: u8);
 
+    /// 从控制台读取一个字符。
+    ///
+    /// 没有输入时返回 `None`。默认实现不支持输入。
+    #[inline]
+    fn get_char(&self) -> Option<u8> {
+        None
+    }
+
     /// 向控制台放置一个字符串。
     ///
     /// 如果使用了锁，覆盖这个实现以免反复获取和释放锁。
@@ -31,16 +40,225 @@ pub trait Console: Sync {
 /// 库找到输出的方法：保存一个对象引用，这是一种单例。
 static CONSOLE: Once<&'static dyn Console> = Once::new();
 
+/// 日志是否显示来源模块。
+static SHOW_TARGET: AtomicBool = AtomicBool::new(true);
+
+/// 日志是否使用 ANSI 颜色。编译时设置了 `NO_COLOR` 环境变量则默认不使用。
+static COLOR: AtomicBool = AtomicBool::new(option_env!("NO_COLOR").is_none());
+
+/// 是否启用行缓冲。
+static BUFFERED: AtomicBool = AtomicBool::new(false);
+
+/// 行缓冲。
+static BUFFER: Mutex<LineBuffer> = Mutex::new(LineBuffer {
+    buf: [0; LINE_CAPACITY],
+    len: 0,
+});
+
+/// 行缓冲容量。
+const LINE_CAPACITY: usize = 256;
+
+/// 环形缓冲的最大容量。
+pub const RING_MAX_CAPACITY: usize = 4096;
+
+/// 环形缓冲，保存最近的输出。
+static RING: Mutex<RingBuffer> = Mutex::new(RingBuffer {
+    buf: [0; RING_MAX_CAPACITY],
+    capacity: 0,
+    head: 0,
+    len: 0,
+});
+
+struct RingBuffer {
+    buf: [u8; RING_MAX_CAPACITY],
+    capacity: usize,
+    head: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    fn push_str(&mut self, s: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        for &c in s.as_bytes() {
+            self.buf[self.head] = c;
+            self.head = (self.head + 1) % self.capacity;
+            self.len = (self.len + 1).min(self.capacity);
+        }
+    }
+
+    /// 按写入顺序输出保存的内容并清空。
+    ///
+    /// 最旧的字符可能已被覆盖了一部分，跳过开头不完整的 UTF-8 字符。
+    fn drain(&mut self, console: &dyn Console) {
+        let start = (self.head + self.capacity - self.len) % self.capacity.max(1);
+        (0..self.len)
+            .map(|i| self.buf[(start + i) % self.capacity])
+            .skip_while(|&c| c & 0xc0 == 0x80)
+            .for_each(|c| console.put_char(c));
+        self.len = 0;
+    }
+}
+
+struct LineBuffer {
+    buf: [u8; LINE_CAPACITY],
+    len: usize,
+}
+
+impl LineBuffer {
+    fn push_str(&mut self, console: &dyn Console, s: &str) {
+        for &c in s.as_bytes() {
+            self.buf[self.len] = c;
+            self.len += 1;
+            if c == b'\n' || self.len == LINE_CAPACITY {
+                self.flush(console);
+            }
+        }
+    }
+
+    /// 输出缓冲中完整的 UTF-8 字符，末尾不完整的字符留在缓冲里。
+    fn flush(&mut self, console: &dyn Console) {
+        let valid = match core::str::from_utf8(&self.buf[..self.len]) {
+            Ok(s) => s.len(),
+            Err(e) => e.valid_up_to(),
+        };
+        console.put_str(unsafe { core::str::from_utf8_unchecked(&self.buf[..valid]) });
+        self.buf.copy_within(valid..self.len, 0);
+        self.len -= valid;
+    }
+}
+
 /// 用户调用这个函数设置输出的方法。
 pub fn init_console(console: &'static dyn Console) {
     CONSOLE.call_once(|| console);
     log::set_logger(&Logger).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+}
+
+/// 从控制台读取一个字符，没有输入时返回 `None`。
+#[inline]
+pub fn getchar() -> Option<u8> {
+    CONSOLE.get().unwrap().get_char()
+}
+
+/// 从控制台读取一个字符，没有输入时轮询等待。
+pub fn getchar_blocking() -> u8 {
+    loop {
+        if let Some(c) = getchar() {
+            return c;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// 从控制台读取一行到 `buf`，回显输入的字符，返回读到的字节数，不包括行尾。
+///
+/// 遇到回车或换行时结束。退格（`0x7f` 或 `0x08`）擦除上一个字符，缓冲为空时忽略。
+/// 只接受可打印的 ASCII 字符，`buf` 写满后忽略后续字符直到行尾。不分配内存。
+pub fn read_line(buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    loop {
+        match getchar_blocking() {
+            b'\r' | b'\n' => {
+                echo("\n");
+                return len;
+            }
+            0x7f | 0x08 => {
+                if len > 0 {
+                    len -= 1;
+                    echo("\x08 \x08");
+                }
+            }
+            c if (c.is_ascii_graphic() || c == b' ') && len < buf.len() => {
+                buf[len] = c;
+                len += 1;
+                echo((c as char).encode_utf8(&mut [0; 4]));
+            }
+            _ => {}
+        }
+    }
 }
 
-/// 根据环境变量设置日志级别。
-pub fn set_log_level(env: Option<&str>) {
+/// 回显输入，启用行缓冲时也立即输出。
+#[inline]
+fn echo(s: &str) {
+    print!("{s}");
+    flush();
+}
+
+/// 根据环境变量设置日志级别，返回设置后的级别。
+///
+/// 级别名称不区分大小写。`env` 为 `None` 时设置为 `Trace`；无法识别的级别名称不改变当前级别，返回 `None`。
+/// 因此也可以在运行时调用，以调整日志详略。
+pub fn set_log_level(env: Option<&str>) -> Option<log::LevelFilter> {
     use log::LevelFilter as Lv;
-    log::set_max_level(env.and_then(|s| Lv::from_str(s).ok()).unwrap_or(Lv::Trace));
+    let level = match env {
+        Some(s) => Lv::from_str(s).ok()?,
+        None => Lv::Trace,
+    };
+    log::set_max_level(level);
+    Some(level)
+}
+
+/// 设置日志是否显示来源模块，默认显示。
+#[inline]
+pub fn set_show_target(show: bool) {
+    SHOW_TARGET.store(show, Ordering::Relaxed);
+}
+
+/// 设置日志是否使用 ANSI 颜色。
+///
+/// 不使用颜色时以 `E/`、`W/`、`I/`、`D/`、`T/` 前缀区分级别。
+#[inline]
+pub fn set_color(color: bool) {
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+/// 设置是否启用行缓冲，默认不启用。
+///
+/// 启用后，输出积累到换行或缓冲满时才通过 [`Console::put_str`] 一次放置，
+/// 因此控制台应覆盖 [`Console::put_str`] 实现批量输出。关闭时输出缓冲中剩余的内容。
+pub fn set_buffered(buffered: bool) {
+    let mut buffer = BUFFER.lock();
+    if let (false, Some(console)) = (buffered, CONSOLE.get()) {
+        buffer.flush(*console);
+    }
+    BUFFERED.store(buffered, Ordering::Relaxed);
+}
+
+/// 输出行缓冲中剩余的内容。
+///
+/// 在关机或异常处理等不再输出的场合之前调用，以免丢失最后一行。
+/// 缓冲正被占用时（例如在输出过程中发生异常）什么也不做，以免死锁。
+pub fn flush() {
+    if let (Some(console), Some(mut buffer)) = (CONSOLE.get(), BUFFER.try_lock()) {
+        buffer.flush(*console);
+    }
+}
+
+/// 设置环形缓冲的容量，返回实际设置的容量。
+///
+/// 启用后，所有输出同时保存到环形缓冲，只保留最近的 `capacity` 字节，
+/// 以便在正常输出丢失时（例如行缓冲中的内容来不及输出）用 [`dump_ring`] 找回。
+/// 容量超过 [`RING_MAX_CAPACITY`] 时取最大容量，为 0 时关闭。重新设置会清空已保存的内容。
+pub fn set_ring_buffer(capacity: usize) -> usize {
+    let mut ring = RING.lock();
+    ring.capacity = capacity.min(RING_MAX_CAPACITY);
+    ring.head = 0;
+    ring.len = 0;
+    ring.capacity
+}
+
+/// 将环形缓冲保存的内容直接输出到控制台并清空。
+///
+/// 先输出行缓冲中剩余的内容；不经过环形缓冲本身，因此不会重复保存。
+/// 环形缓冲正被占用时（例如在输出过程中发生异常）什么也不做，以免死锁。
+pub fn dump_ring() {
+    flush();
+    if let (Some(console), Some(mut ring)) = (CONSOLE.get(), RING.try_lock()) {
+        ring.drain(*console);
+    }
 }
 
 /// 打印一些测试信息。
@@ -62,6 +280,59 @@ pub fn test_log() {
     println!();
 }
 
+/// 以每行 16 字节的十六进制和 ASCII 形式打印 `bytes`，行首是从 `base` 起算的地址。
+///
+/// 接受切片而不是地址，因此调用者负责保证内存已经映射。
+pub fn hexdump(base: usize, bytes: &[u8]) {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        print!("{:#018x} ", base + i * 16);
+        for j in 0..16 {
+            match line.get(j) {
+                Some(b) => print!(" {b:02x}"),
+                None => print!("   "),
+            }
+        }
+        print!("  |");
+        for &b in line {
+            let c = if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            };
+            print!("{c}");
+        }
+        println!("|");
+    }
+}
+
+/// 不经过格式化，直接向控制台放置字节。
+///
+/// 用于格式化机制本身不可靠的场合。控制台尚未初始化时什么也不做。
+#[inline]
+pub fn emergency_print(bytes: &[u8]) {
+    if let Some(console) = CONSOLE.get() {
+        bytes.iter().for_each(|&c| console.put_char(c));
+    }
+}
+
+/// 向控制台写入的 [`Write`] 实现，与 `print!` 一样遵循行缓冲的设置。
+///
+/// 用于不方便使用宏的场合，例如 `let _ = write!(rcore_console::writer(), "{x}");`。
+pub struct ConsoleWriter;
+
+impl Write for ConsoleWriter {
+    #[inline]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        Logger.write_str(s)
+    }
+}
+
+/// 获取向控制台写入的 [`ConsoleWriter`]。
+#[inline]
+pub fn writer() -> ConsoleWriter {
+    ConsoleWriter
+}
+
 /// 打印。
 ///
 /// 给宏用的，用户不会直接调它。
@@ -96,7 +367,18 @@ struct Logger;
 impl Write for Logger {
     #[inline]
     fn write_str(&mut self, s: &str) -> Result<(), fmt::Error> {
-        let _ = CONSOLE.get().unwrap().put_str(s);
+        let console = *CONSOLE.get().unwrap();
+        if let Some(mut ring) = RING.try_lock() {
+            ring.push_str(s);
+        }
+        if !BUFFERED.load(Ordering::Relaxed) {
+            console.put_str(s);
+        } else if let Some(mut buffer) = BUFFER.try_lock() {
+            buffer.push_str(console, s);
+        } else {
+            // 缓冲被占用时可能是在持有缓冲时中断或 panic，等待会死锁，直接输出
+            console.put_str(s);
+        }
         Ok(())
     }
 }
@@ -111,19 +393,32 @@ impl log::Log for Logger {
     #[inline]
     fn log(&self, record: &log::Record) {
         use log::Level::*;
-        let color_code: u8 = match record.level() {
-            Error => 31,
-            Warn => 93,
-            Info => 34,
-            Debug => 32,
-            Trace => 90,
+        let (color_code, tag): (u8, char) = match record.level() {
+            Error => (31, 'E'),
+            Warn => (93, 'W'),
+            Info => (34, 'I'),
+            Debug => (32, 'D'),
+            Trace => (90, 'T'),
         };
-        println!(
-            "\x1b[{color_code}m[{:>5}] {}\x1b[0m",
-            record.level(),
-            record.args(),
-        );
+        let color = COLOR.load(Ordering::Relaxed);
+        if color {
+            print!("\x1b[{color_code}m[{:>5}] ", record.level());
+        } else {
+            print!("{tag}/ ");
+        }
+        if SHOW_TARGET.load(Ordering::Relaxed) {
+            print!("[{}] ", record.target());
+        }
+        print!("{}", record.args());
+        if color {
+            println!("\x1b[0m");
+        } else {
+            println!();
+        }
     }
 
-    fn flush(&self) {}
+    #[inline]
+    fn flush(&self) {
+        flush();
+    }
 }
@@ -1,65 +1,892 @@
 //! 内存分配。
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![deny(warnings, missing_docs)]
 
 extern crate alloc;
 
-use alloc::alloc::handle_alloc_error;
+#[cfg(any(test, feature = "self-test"))]
+mod self_test;
+
+#[cfg(test)]
+mod test;
+
+#[cfg(feature = "self-test")]
+pub use self_test::self_test;
+
 use core::{
-    alloc::{GlobalAlloc, Layout},
+    alloc::Layout,
+    fmt,
     ptr::NonNull,
+    sync::atomic::{self, AtomicUsize, Ordering},
 };
 use customizable_buddy::{BuddyAllocator, LinkedListBuddy, UsizeBuddy};
+use spin::Mutex;
 
 /// 初始化内存分配。
 ///
-/// 参数 `base_address` 表示动态内存区域的起始位置。
+/// 参数 `base_address` 表示动态内存区域的起始位置。不合法时 panic，见 [`try_init`]。
 #[inline]
 pub fn init(base_address: usize) {
+    try_init(base_address).unwrap_or_else(|e| panic!("failed to init heap: {e}"));
+}
+
+/// 初始化内存分配，检查起始位置。
+///
+/// 最小块大小为 `usize` 的大小。起始位置不能为 0，且必须按分配器的最小块大小对齐。
+#[inline]
+pub fn try_init(base_address: usize) -> Result<(), InitError> {
+    try_init_with_order(base_address, MIN_BLOCK.trailing_zeros() as _)
+}
+
+/// 以 `1 << min_order` 字节为最小块大小初始化内存分配，检查参数。
+///
+/// 最小块至少要能放下一个 `usize`，一次分配的上限 `1 << (min_order + ORDERS + OLIGARCHY_BITS)`
+/// 不能超出地址宽度，见 [`max_order`]，否则返回 [`InitError::InvalidOrder`]。
+/// 起始位置不能为 0，且必须按最小块大小对齐。
+pub fn try_init_with_order(base_address: usize, min_order: usize) -> Result<(), InitError> {
+    if min_order < MIN_BLOCK.trailing_zeros() as usize
+        || min_order + ORDERS + OLIGARCHY_BITS >= usize::BITS as usize
+    {
+        return Err(InitError::InvalidOrder);
+    }
+    if base_address == 0 || base_address & ((1 << min_order) - 1) != 0 {
+        return Err(InitError::Misaligned);
+    }
     unsafe {
-        HEAP.init(
-            core::mem::size_of::<usize>().trailing_zeros() as _,
-            NonNull::new(base_address as *mut u8).unwrap(),
-        )
-    };
+        HEAP.init(min_order, NonNull::new(base_address as *mut u8).unwrap());
+        MIN_ORDER = min_order;
+        BASE = base_address;
+        TRANSFERRED_END = base_address;
+    }
+    Ok(())
+}
+
+/// 分配器最小块大小的阶，最小块为 `1 << min_order()` 字节。初始化之前为 0。
+#[inline]
+pub fn min_order() -> usize {
+    unsafe { MIN_ORDER }
+}
+
+/// 一次分配的最大阶，一次分配最多 `1 << max_order()` 字节。
+///
+/// 伙伴分配器的 [`ORDERS`] 层管理 `min_order()` 到 `min_order() + ORDERS - 1` 阶的块，
+/// 之上的顶层位图管理 `1 << OLIGARCHY_BITS` 个 `min_order() + ORDERS` 阶的大块，可以一次分配其中连续的多块。
+/// 默认最小块下为 3 + 21 + 6 = 30 阶，即 1 GiB。
+#[inline]
+pub fn max_order() -> usize {
+    min_order() + ORDERS + OLIGARCHY_BITS
 }
 
 /// 将一个内存块托管到内存分配器。
 ///
+/// 内存块不合法时 panic，见 [`try_transfer`]。
+///
 /// # Safety
 ///
 /// `region` 内存块的所有权将转移到分配器，因此需要调用者确保这个内存块与已经转移到分配器的内存块都不重叠，且未被其他对象引用。
 /// 并且这个内存块必须位于初始化时传入的起始位置之后。
 #[inline]
 pub unsafe fn transfer(region: &'static mut [u8]) {
+    try_transfer(region).unwrap_or_else(|e| panic!("failed to transfer heap region: {e}"));
+}
+
+/// 将一个内存块托管到内存分配器，检查内存块。
+///
+/// 内存块必须按分配器的最小块大小对齐，不能小于最小块大小，必须位于初始化时传入的起始位置之后，
+/// 且需要按地址升序托管，以便检查与之前托管的内存块不重叠。
+///
+/// # Safety
+///
+/// 与 [`transfer`] 相同。
+pub unsafe fn try_transfer(region: &'static mut [u8]) -> Result<(), InitError> {
+    let start = region.as_ptr() as usize;
+    if BASE == 0 {
+        return Err(InitError::Uninitialized);
+    }
+    let min_block = 1 << MIN_ORDER;
+    if start % min_block != 0 {
+        return Err(InitError::Misaligned);
+    }
+    if region.len() < min_block {
+        return Err(InitError::TooSmall);
+    }
+    if start < TRANSFERRED_END {
+        return Err(InitError::Overlapping);
+    }
+    let end = start + region.len();
+    #[cfg(feature = "double_free_check")]
+    let region = track(region)?;
     let ptr = NonNull::new(region.as_mut_ptr()).unwrap();
     HEAP.transfer(ptr, region.len());
+    TOTAL += region.len();
+    TRANSFERRED_END = end;
+    Ok(())
+}
+
+/// 从内存块末尾划出它的存活分配位图，返回剩下交给分配器的部分。
+///
+/// 位图每个最小块一位，约占内存块的 `1 / (8 << min_order())`。托管的内存块全部被检查，
+/// 数量超过 [`TRACKED_REGIONS`] 时返回 [`InitError::TooManyRegions`]。
+#[cfg(feature = "double_free_check")]
+unsafe fn track(region: &'static mut [u8]) -> Result<&'static mut [u8], InitError> {
+    if TRACKED_LEN == TRACKED_REGIONS {
+        return Err(InitError::TooManyRegions);
+    }
+    let start = region.as_ptr() as usize;
+    let end = start + region.len();
+    let min_block = 1 << MIN_ORDER;
+    let words = ((region.len() >> MIN_ORDER) + usize::BITS as usize - 1) / usize::BITS as usize;
+    let bitmap = (end - words * core::mem::size_of::<usize>()) & !(min_block - 1);
+    if bitmap < start + min_block {
+        return Err(InitError::TooSmall);
+    }
+    let live = core::slice::from_raw_parts_mut(bitmap as *mut usize, words);
+    live.fill(0);
+    TRACKED[TRACKED_LEN] = Tracked {
+        start,
+        end: bitmap,
+        live: live.as_mut_ptr(),
+    };
+    TRACKED_LEN += 1;
+    Ok(&mut region[..bitmap - start])
+}
+
+/// 初始化内存分配失败的原因。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InitError {
+    /// 还没有调用 [`init`]。
+    Uninitialized,
+    /// 地址没有按最小块大小对齐。
+    Misaligned,
+    /// 内存块小于最小块大小。
+    TooSmall,
+    /// 内存块位于起始位置之前，或与之前托管的内存块重叠。
+    Overlapping,
+    /// 最小块的阶不合法。
+    InvalidOrder,
+    /// 启用 `double_free_check` 时托管的内存块过多。
+    TooManyRegions,
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Uninitialized => write!(f, "heap not initialized"),
+            Self::Misaligned => write!(f, "address not aligned to the minimum block"),
+            Self::TooSmall => write!(f, "region smaller than the minimum block"),
+            Self::Overlapping => write!(f, "region overlaps a transferred region"),
+            Self::InvalidOrder => write!(f, "invalid minimum block order"),
+            Self::TooManyRegions => write!(f, "too many regions to track"),
+        }
+    }
+}
+
+/// 分配器默认的最小块大小，也是允许的最小块大小的下限。
+const MIN_BLOCK: usize = core::mem::size_of::<usize>();
+
+/// 伙伴分配器的阶数。
+pub const ORDERS: usize = 21;
+
+/// 顶层位图管理的大块数量的阶，位图是一个 `usize`。
+const OLIGARCHY_BITS: usize = usize::BITS.trailing_zeros() as _;
+
+// 在编译时检查阶数与块大小相容。
+// 页分配和 `transfer` 托管的内存都以页为单位，默认最小块下的最大块 `1 << (3 + ORDERS)`
+// 必须至少是一页，否则 `allocate_pages` 总是失败，托管的内存也只能切成小块使用；
+// 同时一次分配的上限不能超出地址宽度，否则 `try_init` 用默认最小块初始化也会失败。
+const _: () = {
+    let min = MIN_BLOCK.trailing_zeros() as usize;
+    assert!(min + ORDERS >= PAGE_BITS, "max block smaller than a page");
+    assert!(
+        min + ORDERS + OLIGARCHY_BITS < usize::BITS as usize,
+        "max allocation exceeds address width"
+    );
+};
+
+/// 设置释放内存时填充的字节，默认为 0。
+///
+/// 可设置为非零的毒化字节，使释放后的读取更容易暴露。
+#[cfg(feature = "zero_on_free")]
+#[inline]
+pub fn set_fill_byte(byte: u8) {
+    unsafe { FILL_BYTE = byte };
+}
+
+/// 设置全局分配器分配失败时调用的函数。
+///
+/// 在 [`handle_alloc_error`](alloc::alloc::handle_alloc_error) 之前调用，参数是失败的分配。
+/// 之后的 panic 处理要经过格式化，`hook` 可以先不经格式化报告错误，也可以不返回而直接关机。
+#[inline]
+pub fn set_alloc_error_hook(hook: fn(Layout)) {
+    unsafe { ALLOC_ERROR_HOOK = Some(hook) };
+}
+
+/// 设置堆的分配预算，`None` 表示不限制，默认不限制。
+///
+/// 设置后，使已分配字节数超过 `limit` 的分配失败，就像堆只有这么大：[`allocate_pages`] 等接口返回
+/// [`AllocError::OutOfMemory`]，全局分配器调用 [`handle_alloc_error`](alloc::alloc::handle_alloc_error)。
+/// 用于在测试中确定地耗尽一个小堆，检查内存不足的处理。预算只限制之后的分配，不影响已分配的内存，
+/// 托管的内存块也不变，因此随时可以放宽或取消。
+#[inline]
+pub fn set_alloc_limit(limit: Option<usize>) {
+    LIMIT.store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+}
+
+/// 堆使用情况。
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    /// 托管到分配器的总字节数。
+    pub total_bytes: usize,
+    /// 已分配的字节数。
+    pub allocated_bytes: usize,
+    /// 未分配的字节数。
+    pub free_bytes: usize,
+    /// 同时分配的字节数的历史最大值。
+    pub peak_allocated_bytes: usize,
+    /// 一次能分配的最大连续字节数，见 [`largest_free_block`]。
+    pub largest_free_block: usize,
+}
+
+/// 查询堆使用情况。
+///
+/// 按分配时请求的 [`Layout`] 大小统计，不包括伙伴分配器向上取整造成的浪费。
+#[inline]
+pub fn heap_stats() -> HeapStats {
+    let total = unsafe { TOTAL };
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    let peak = PEAK.load(Ordering::Relaxed);
+    HeapStats {
+        total_bytes: total,
+        allocated_bytes: allocated,
+        free_bytes: total - allocated,
+        peak_allocated_bytes: peak,
+        largest_free_block: largest_free_block(),
+    }
+}
+
+/// 伙伴分配器中最大的空闲块的字节数，即一次能分配的最大连续内存。
+///
+/// 伙伴分配器没有公开各阶的空闲链表，因此在锁内从不超过未分配字节数的最高阶开始，
+/// 逐阶试分配一个对齐的块并立即归还，第一个成功的阶就是最大空闲块。试分配不经过用量统计。
+pub fn largest_free_block() -> usize {
+    let mut heap = HEAP.0.lock();
+    let free = heap_free_bytes();
+    if free == 0 {
+        return 0;
+    }
+    let top = (usize::BITS - 1 - free.leading_zeros()) as usize;
+    let min = min_order();
+    for order in (min..=top.min(max_order())).rev() {
+        let layout = Layout::from_size_align(1 << order, 1 << order).unwrap();
+        // 试分配的块立即归还，不会被其他代码看到
+        unsafe {
+            if let Ok((ptr, _)) = heap.0.allocate_layout::<u8>(layout) {
+                heap.0.deallocate_layout(ptr, layout);
+                return 1 << order;
+            }
+        }
+    }
+    0
+}
+
+/// 未分配且不超出分配预算的字节数。
+#[inline]
+fn heap_free_bytes() -> usize {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    unsafe { TOTAL }
+        .min(limit)
+        .saturating_sub(ALLOCATED.load(Ordering::Relaxed))
+}
+
+/// 堆上一次能分配的连续物理页数。
+///
+/// 由 [`largest_free_block`] 换算。伙伴分配器中的碎片使未分配的字节不一定能连续分配，
+/// 因此不按未分配的总字节数估计。用于在大量分配之前提前发现内存不足，结果偏保守。
+#[inline]
+pub fn free_page_count() -> usize {
+    largest_free_block() >> PAGE_BITS
+}
+
+/// 直方图的阶数，与伙伴分配器的阶数相同。
+#[cfg(feature = "alloc_stats")]
+pub const HISTOGRAM_ORDERS: usize = ORDERS;
+
+/// 查询按阶统计的存活分配数。
+///
+/// 第 `i` 项是向上取整后大小为 `MIN_BLOCK << i` 的存活分配数，超出范围的大分配计入最后一项。
+/// 计数从上一次 [`reset_histogram`] 起算，之前分配的内存在之后释放不会使计数变为负数。
+#[cfg(feature = "alloc_stats")]
+#[inline]
+pub fn alloc_histogram() -> [usize; HISTOGRAM_ORDERS] {
+    unsafe { HISTOGRAM }
+}
+
+/// 清空按阶统计的存活分配数。
+#[cfg(feature = "alloc_stats")]
+#[inline]
+pub fn reset_histogram() {
+    unsafe { HISTOGRAM = [0; HISTOGRAM_ORDERS] };
+}
+
+/// 计算分配在直方图中的阶。
+#[cfg(feature = "alloc_stats")]
+#[inline]
+fn histogram_order(layout: Layout) -> usize {
+    let size = layout.size().max(layout.align()).max(MIN_BLOCK);
+    let order = size.next_power_of_two().trailing_zeros() - MIN_BLOCK.trailing_zeros();
+    (order as usize).min(HISTOGRAM_ORDERS - 1)
+}
+
+/// 计算 `ptr` 在存活分配位图中的位置，`ptr` 不在任何托管的内存块中时返回 `None`。
+///
+/// 分配的起始位置总是按最小块对齐，因此以最小块为单位就能区分不同的分配。
+#[cfg(feature = "double_free_check")]
+#[inline]
+unsafe fn live_bit(ptr: *const u8) -> Option<(*mut usize, usize)> {
+    let addr = ptr as usize;
+    let region = (*core::ptr::addr_of!(TRACKED))[..TRACKED_LEN]
+        .iter()
+        .find(|r| r.start <= addr && addr < r.end)?;
+    let index = (addr - region.start) >> MIN_ORDER;
+    let bits = usize::BITS as usize;
+    Some((region.live.add(index / bits), 1 << (index % bits)))
+}
+
+/// 释放 `ptr` 能否通过检查：`ptr` 是一个存活分配的起始位置。
+///
+/// 托管的内存块全部被检查，不在其中的指针一定不是分配器分配的，因此不能释放。
+#[cfg(feature = "double_free_check")]
+#[inline]
+unsafe fn free_allowed(ptr: *const u8) -> bool {
+    match live_bit(ptr) {
+        Some((word, bit)) => *word & bit != 0,
+        None => false,
+    }
+}
+
+/// 物理页大小的位数。
+pub const PAGE_BITS: usize = 12;
+
+/// 分配物理页失败的原因。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AllocError {
+    /// 动态内存不足。
+    OutOfMemory,
+    /// 请求的页数过多，无法构造合法的 [`Layout`]。
+    LayoutOverflow,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfMemory => write!(f, "out of memory"),
+            Self::LayoutOverflow => write!(f, "page count overflows layout"),
+        }
+    }
+}
+
+/// 分配 `count` 个连续的物理页，并清零。
+///
+/// 物理页按页对齐。堆上没有足够的连续空间时返回 [`AllocError::OutOfMemory`]。
+pub fn allocate_pages(count: usize) -> Result<NonNull<u8>, AllocError> {
+    let layout = page_layout(count).ok_or(AllocError::LayoutOverflow)?;
+    let ptr = unsafe { try_alloc(layout) }.ok_or(AllocError::OutOfMemory)?;
+    unsafe { zero_pages(ptr, count) };
+    Ok(ptr)
+}
+
+/// 将 `ptr` 起的 `count` 个物理页清零。
+///
+/// 按 `usize` 逐字写入，比逐字节清零快。新分配的页表页必须清零，否则残留的数据会被当作有效的页表项。
+///
+/// # Safety
+///
+/// `ptr` 必须按页对齐，且指向 `count` 个可写的页。
+#[inline]
+pub unsafe fn zero_pages(ptr: NonNull<u8>, count: usize) {
+    let words = (count << PAGE_BITS) / core::mem::size_of::<usize>();
+    core::slice::from_raw_parts_mut(ptr.as_ptr().cast::<usize>(), words).fill(0);
+}
+
+/// 释放 [`allocate_pages`] 分配的 `count` 个物理页。
+///
+/// # Safety
+///
+/// `ptr` 必须是 [`allocate_pages`] 以相同的 `count` 分配的，且未被释放过。
+pub unsafe fn deallocate_pages(ptr: NonNull<u8>, count: usize) {
+    free(ptr, page_layout(count).unwrap());
+}
+
+/// 拷贝的范围超出了物理页。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CopyError;
+
+impl fmt::Display for CopyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "copy past the end of the page")
+    }
+}
+
+/// 独占一个物理页，离开作用域时释放。
+///
+/// 物理页的所有权转移给页表等长期持有者时，用 [`FrameBox::leak`] 放弃自动释放。
+pub struct FrameBox(NonNull<u8>);
+
+impl FrameBox {
+    /// 分配一个清零的物理页。
+    #[inline]
+    pub fn new() -> Result<Self, AllocError> {
+        allocate_pages(1).map(Self)
+    }
+
+    /// 物理页的起始地址。
+    #[inline]
+    pub fn as_ptr(&self) -> NonNull<u8> {
+        self.0
+    }
+
+    /// 物理页号。
+    ///
+    /// 内核动态内存恒等映射，因此物理页号直接由地址得出。
+    #[inline]
+    pub fn ppn(&self) -> usize {
+        self.0.as_ptr() as usize >> PAGE_BITS
+    }
+
+    /// 以字节数组访问物理页。
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8; 1 << PAGE_BITS] {
+        unsafe { self.0.cast().as_ref() }
+    }
+
+    /// 以可变字节数组访问物理页。
+    #[inline]
+    pub fn as_bytes_mut(&mut self) -> &mut [u8; 1 << PAGE_BITS] {
+        unsafe { self.0.cast().as_mut() }
+    }
+
+    /// 将 `src` 拷贝到物理页的 `offset` 处。
+    ///
+    /// 超出页尾时不拷贝任何数据并返回错误。
+    pub fn copy_in(&mut self, offset: usize, src: &[u8]) -> Result<(), CopyError> {
+        offset
+            .checked_add(src.len())
+            .and_then(|end| self.as_bytes_mut().get_mut(offset..end))
+            .ok_or(CopyError)?
+            .copy_from_slice(src);
+        Ok(())
+    }
+
+    /// 放弃所有权，返回物理页的起始地址。之后需要用 [`deallocate_pages`] 释放。
+    #[inline]
+    pub fn leak(self) -> NonNull<u8> {
+        let ptr = self.0;
+        core::mem::forget(self);
+        ptr
+    }
+}
+
+impl Drop for FrameBox {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { deallocate_pages(self.0, 1) };
+    }
+}
+
+/// 多个持有者共享的物理页，最后一个持有者离开作用域时释放。
+///
+/// 引用计数是原子的，另外从堆上分配。用于写时复制：多个地址空间以只读方式映射同一个物理页，
+/// 写缺页时如果 [`SharedFrame::try_unique`] 成功就直接恢复写权限，否则复制一份。
+pub struct SharedFrame {
+    page: NonNull<u8>,
+    count: NonNull<AtomicUsize>,
+}
+
+unsafe impl Send for SharedFrame {}
+unsafe impl Sync for SharedFrame {}
+
+impl SharedFrame {
+    /// 分配一个清零的物理页，引用计数为 1。
+    #[inline]
+    pub fn new() -> Result<Self, AllocError> {
+        FrameBox::new().and_then(Self::from_box)
+    }
+
+    /// 从独占的物理页创建，引用计数为 1。分配引用计数失败时释放物理页。
+    pub fn from_box(frame: FrameBox) -> Result<Self, AllocError> {
+        let layout = Layout::new::<AtomicUsize>();
+        let count = unsafe { try_alloc(layout) }
+            .ok_or(AllocError::OutOfMemory)?
+            .cast::<AtomicUsize>();
+        unsafe { count.as_ptr().write(AtomicUsize::new(1)) };
+        Ok(Self {
+            page: frame.leak(),
+            count,
+        })
+    }
+
+    /// 物理页的起始地址。
+    #[inline]
+    pub fn as_ptr(&self) -> NonNull<u8> {
+        self.page
+    }
+
+    /// 物理页号。
+    #[inline]
+    pub fn ppn(&self) -> usize {
+        self.page.as_ptr() as usize >> PAGE_BITS
+    }
+
+    /// 以字节数组访问物理页。共享的页只能读。
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8; 1 << PAGE_BITS] {
+        unsafe { self.page.cast().as_ref() }
+    }
+
+    /// 当前的持有者数。
+    #[inline]
+    pub fn ref_count(&self) -> usize {
+        self.count().load(Ordering::Acquire)
+    }
+
+    /// 如果自己是唯一的持有者，转为独占的物理页，否则原样返回。
+    pub fn try_unique(self) -> Result<FrameBox, Self> {
+        if self.ref_count() != 1 {
+            return Err(self);
+        }
+        let this = core::mem::ManuallyDrop::new(self);
+        unsafe { free(this.count.cast(), Layout::new::<AtomicUsize>()) };
+        Ok(FrameBox(this.page))
+    }
+
+    #[inline]
+    fn count(&self) -> &AtomicUsize {
+        unsafe { self.count.as_ref() }
+    }
+}
+
+impl Clone for SharedFrame {
+    /// 增加一个持有者。
+    #[inline]
+    fn clone(&self) -> Self {
+        self.count().fetch_add(1, Ordering::Relaxed);
+        Self {
+            page: self.page,
+            count: self.count,
+        }
+    }
+}
+
+impl Drop for SharedFrame {
+    fn drop(&mut self) {
+        if self.count().fetch_sub(1, Ordering::Release) == 1 {
+            // 与其他持有者的释放同步，保证它们对页的访问都已结束
+            atomic::fence(Ordering::Acquire);
+            unsafe {
+                free(self.count.cast(), Layout::new::<AtomicUsize>());
+                deallocate_pages(self.page, 1);
+            }
+        }
+    }
+}
+
+/// 分配 `count` 个物理上连续的页，并清零，返回起始地址和起始物理页号。
+///
+/// 起始地址按 `count` 向上取整到 2 的幂的页数对齐，可用于大页或设备映射。
+/// 内核动态内存恒等映射，因此物理页号直接由地址得出。
+///
+/// 能分配的最大连续区域不超过 `1 << max_order()` 字节，默认最小块下为 1 GiB，见 [`max_order`]，
+/// 实际还不能超过单次 [`transfer`] 托管的内存块大小。
+pub fn allocate_contiguous(count: usize) -> Result<(NonNull<u8>, usize), AllocError> {
+    let layout = contiguous_layout(count).ok_or(AllocError::LayoutOverflow)?;
+    let ptr = unsafe { try_alloc(layout) }.ok_or(AllocError::OutOfMemory)?;
+    unsafe { zero_pages(ptr, count) };
+    Ok((ptr, ptr.as_ptr() as usize >> PAGE_BITS))
+}
+
+/// 释放 [`allocate_contiguous`] 分配的 `count` 个物理页。
+///
+/// # Safety
+///
+/// `ptr` 必须是 [`allocate_contiguous`] 以相同的 `count` 分配的，且未被释放过。
+pub unsafe fn deallocate_contiguous(ptr: NonNull<u8>, count: usize) {
+    free(ptr, contiguous_layout(count).unwrap());
+}
+
+#[inline]
+fn page_layout(count: usize) -> Option<Layout> {
+    let size = count.checked_mul(1 << PAGE_BITS)?;
+    Layout::from_size_align(size, 1 << PAGE_BITS).ok()
+}
+
+#[inline]
+fn contiguous_layout(count: usize) -> Option<Layout> {
+    let size = count.checked_mul(1 << PAGE_BITS)?;
+    let align = count
+        .checked_next_power_of_two()?
+        .checked_mul(1 << PAGE_BITS)?;
+    Layout::from_size_align(size, align).ok()
+}
+
+/// 不依赖阶数等类型参数的伙伴分配器接口。
+///
+/// 不同阶数的 [`BuddyAllocator`] 是不同的类型，通过这个 trait 可以统一存放和使用，例如组成多区域的分配器。
+/// 实现在内部加锁，因此只需要共享引用。
+pub trait DynBuddy {
+    /// 按 `layout` 分配内存，失败时返回 `None`。
+    ///
+    /// # Safety
+    ///
+    /// 分配器必须已经初始化。
+    unsafe fn allocate(&self, layout: Layout) -> Option<NonNull<u8>>;
+
+    /// 释放 [`DynBuddy::allocate`] 以相同 `layout` 分配的内存。
+    ///
+    /// # Safety
+    ///
+    /// `ptr` 必须由这个分配器以相同的 `layout` 分配，且未被释放过。
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+
+    /// 将一个内存块托管到分配器。
+    ///
+    /// # Safety
+    ///
+    /// 与 [`transfer`] 相同，内存块的所有权转移到分配器。
+    unsafe fn transfer(&self, ptr: NonNull<u8>, size: usize);
+}
+
+/// 加锁的伙伴分配器。
+pub struct LockedBuddy<const N: usize>(Mutex<Buddy<N>>);
+
+/// 伙伴分配器的空闲链表由裸指针串起，但空闲块都归分配器所有，加锁后可以在核间共享。
+struct Buddy<const N: usize>(BuddyAllocator<N, UsizeBuddy, LinkedListBuddy>);
+
+unsafe impl<const N: usize> Send for Buddy<N> {}
+
+impl<const N: usize> LockedBuddy<N> {
+    /// 创建空的分配器，使用之前需要 [`LockedBuddy::init`]。
+    #[inline]
+    pub const fn new() -> Self {
+        Self(Mutex::new(Buddy(BuddyAllocator::new())))
+    }
+
+    /// 以 `1 << min_order` 字节为最小块大小、`base` 为起始位置初始化分配器。
+    ///
+    /// # Safety
+    ///
+    /// 与 [`try_init_with_order`] 的参数要求相同。
+    #[inline]
+    pub unsafe fn init(&self, min_order: usize, base: NonNull<u8>) {
+        self.0.lock().0.init(min_order, base);
+    }
+}
+
+impl<const N: usize> Default for LockedBuddy<N> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> DynBuddy for LockedBuddy<N> {
+    #[inline]
+    unsafe fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let mut buddy = self.0.lock();
+        buddy
+            .0
+            .allocate_layout::<u8>(layout)
+            .ok()
+            .map(|(ptr, _)| ptr)
+    }
+
+    #[inline]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.0.lock().0.deallocate_layout(ptr, layout);
+    }
+
+    #[inline]
+    unsafe fn transfer(&self, ptr: NonNull<u8>, size: usize) {
+        self.0.lock().0.transfer(ptr, size);
+    }
+}
+
+/// 内核堆的 [`DynBuddy`] 接口，经过与全局分配器相同的用量统计和重复释放检查。
+struct GlobalHeap;
+
+impl DynBuddy for GlobalHeap {
+    #[inline]
+    unsafe fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        try_alloc(layout)
+    }
+
+    #[inline]
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        free(ptr, layout);
+    }
+
+    #[inline]
+    unsafe fn transfer(&self, ptr: NonNull<u8>, size: usize) {
+        transfer(core::slice::from_raw_parts_mut(ptr.as_ptr(), size));
+    }
+}
+
+/// 以 [`DynBuddy`] 访问内核堆分配器。
+///
+/// 与全局分配器共用同一个加锁的分配器，分配和释放同样计入 [`heap_stats`]，可以与全局分配器混用。
+#[inline]
+pub fn global_heap() -> &'static dyn DynBuddy {
+    &GlobalHeap
 }
 
 /// 堆分配器。
 ///
-/// 最大容量：6 + 21 + 3 = 30 -> 1 GiB。
-/// 不考虑并发使用，因此没有加锁。
-static mut HEAP: BuddyAllocator<21, UsizeBuddy, LinkedListBuddy> = BuddyAllocator::new();
+/// 最大容量：6 + 21 + 3 = 30 -> 1 GiB，也是一次分配的上限，见 [`max_order`]。
+/// 分配、释放和存活分配的记录都在它的锁内进行。
+static HEAP: LockedBuddy<ORDERS> = LockedBuddy::new();
+
+/// 释放内存时填充的字节。
+#[cfg(feature = "zero_on_free")]
+static mut FILL_BYTE: u8 = 0;
+
+/// 按阶统计的存活分配数。
+#[cfg(feature = "alloc_stats")]
+static mut HISTOGRAM: [usize; HISTOGRAM_ORDERS] = [0; HISTOGRAM_ORDERS];
+
+/// 启用 `double_free_check` 时最多能托管的内存块数。
+#[cfg(feature = "double_free_check")]
+pub const TRACKED_REGIONS: usize = 8;
+
+/// 一个托管的内存块和它的存活分配位图。
+#[cfg(feature = "double_free_check")]
+#[derive(Clone, Copy)]
+struct Tracked {
+    /// 交给分配器的范围的起始位置。
+    start: usize,
+    /// 交给分配器的范围的结束位置，位图紧随其后。
+    end: usize,
+    /// 存活分配的起始位置，每个最小块一位。
+    live: *mut usize,
+}
+
+/// 托管的内存块，按地址升序排列。
+#[cfg(feature = "double_free_check")]
+static mut TRACKED: [Tracked; TRACKED_REGIONS] = [Tracked {
+    start: 0,
+    end: 0,
+    live: core::ptr::null_mut(),
+}; TRACKED_REGIONS];
+
+/// 托管的内存块数。
+#[cfg(feature = "double_free_check")]
+static mut TRACKED_LEN: usize = 0;
+
+/// 初始化时设置的最小块大小的阶。
+static mut MIN_ORDER: usize = 0;
+
+/// 初始化时传入的起始位置。
+static mut BASE: usize = 0;
+
+/// 已托管的内存块的最高结束地址。
+static mut TRANSFERRED_END: usize = 0;
+
+/// 托管到堆的总字节数。
+static mut TOTAL: usize = 0;
+
+/// 堆上已分配的字节数。
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// 堆上已分配的字节数的历史最大值。
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// 分配失败时调用的函数，见 [`set_alloc_error_hook`]。
+static mut ALLOC_ERROR_HOOK: Option<fn(Layout)> = None;
+
+/// 分配预算，见 [`set_alloc_limit`]。
+static LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// 从堆上按 `layout` 分配内存并记录用量，堆空间不足时返回 `None`。
+///
+/// 全局分配器在失败时调用 [`handle_alloc_error`] 而不返回，需要报告失败的接口直接调用这个函数。
+pub(crate) unsafe fn try_alloc(layout: Layout) -> Option<NonNull<u8>> {
+    let mut heap = HEAP.0.lock();
+    if ALLOCATED
+        .load(Ordering::Relaxed)
+        .saturating_add(layout.size())
+        > LIMIT.load(Ordering::Relaxed)
+    {
+        return None;
+    }
+    let (ptr, _) = heap.0.allocate_layout::<u8>(layout).ok()?;
+    let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+    PEAK.fetch_max(allocated, Ordering::Relaxed);
+    #[cfg(feature = "alloc_stats")]
+    {
+        HISTOGRAM[histogram_order(layout)] += 1;
+    }
+    #[cfg(feature = "double_free_check")]
+    if let Some((word, bit)) = live_bit(ptr.as_ptr()) {
+        assert!(
+            *word & bit == 0,
+            "allocated {:#x} twice",
+            ptr.as_ptr() as usize
+        );
+        *word |= bit;
+    }
+    drop(heap);
+    Some(ptr)
+}
+
+/// 将 [`try_alloc`] 分配的内存还给堆并记录用量。
+pub(crate) unsafe fn free(ptr: NonNull<u8>, layout: Layout) {
+    let mut heap = HEAP.0.lock();
+    #[cfg(feature = "double_free_check")]
+    {
+        assert!(
+            free_allowed(ptr.as_ptr()),
+            "double free or free of unallocated memory at {:#x}",
+            ptr.as_ptr() as usize
+        );
+        if let Some((word, bit)) = live_bit(ptr.as_ptr()) {
+            *word &= !bit;
+        }
+    }
+    #[cfg(feature = "zero_on_free")]
+    ptr.as_ptr().write_bytes(FILL_BYTE, layout.size());
+    heap.0.deallocate_layout(ptr, layout);
+    ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    #[cfg(feature = "alloc_stats")]
+    {
+        let order = histogram_order(layout);
+        HISTOGRAM[order] = HISTOGRAM[order].saturating_sub(1);
+    }
+}
 
+/// 内核的全局分配器。在宿主机上测试时使用标准库的分配器，堆只通过 [`try_alloc`] 和 [`free`] 访问。
+#[cfg(not(test))]
 struct Global;
 
+#[cfg(not(test))]
 #[global_allocator]
 static GLOBAL: Global = Global;
 
-unsafe impl GlobalAlloc for Global {
+#[cfg(not(test))]
+unsafe impl core::alloc::GlobalAlloc for Global {
     #[inline]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if let Ok((ptr, _)) = HEAP.allocate_layout::<u8>(layout) {
-            ptr.as_ptr()
-        } else {
-            handle_alloc_error(layout)
+        match try_alloc(layout) {
+            Some(ptr) => ptr.as_ptr(),
+            None => {
+                if let Some(hook) = ALLOC_ERROR_HOOK {
+                    hook(layout);
+                }
+                alloc::alloc::handle_alloc_error(layout)
+            }
         }
     }
 
     #[inline]
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        HEAP.deallocate_layout(NonNull::new(ptr).unwrap(), layout)
+        free(NonNull::new(ptr).unwrap(), layout);
     }
 }
@@ -0,0 +1,125 @@
+use crate::{free, heap_stats, try_alloc, SharedFrame};
+use core::{alloc::Layout, ptr::NonNull};
+
+/// 同时存活的分配数上限。
+const SLOTS: usize = 64;
+
+/// 以随机交错的分配和释放检查堆。
+///
+/// 用 `seed` 初始化伪随机数，执行 `rounds` 次操作：随机选一个槽，空槽就分配一块随机大小和对齐的内存并填充，
+/// 否则检查填充的内容没被改写后释放。每次分配都检查与存活的块不重叠，每次操作后都检查 [`heap_stats`] 与存活的块相符。
+/// 最后释放全部内存，检查没有泄漏，再检查 [`SharedFrame`] 的引用计数。启用 `double_free_check` 时，
+/// 还检查释放过的块再次释放会被拒绝。堆空间不足时跳过这次分配。检查失败时 panic。
+///
+/// 存活的块记录在栈上，检查过程本身不占用堆。不依赖目标平台，也在宿主机上的 `cargo test` 中运行。
+pub fn self_test(rounds: usize, seed: u64) {
+    let mut rng = seed | 1;
+    let mut next = move || {
+        // xorshift64
+        rng ^= rng << 13;
+        rng ^= rng >> 7;
+        rng ^= rng << 17;
+        rng as usize
+    };
+    let baseline = heap_stats().allocated_bytes;
+    let mut live: [Option<(*mut u8, Layout)>; SLOTS] = [None; SLOTS];
+    for _ in 0..rounds {
+        let r = next();
+        let slot = r % SLOTS;
+        match live[slot].take() {
+            Some((ptr, layout)) => unsafe { release(slot, ptr, layout) },
+            None => {
+                let size = 1 + (r >> 8) % 4096;
+                let align = 1 << ((r >> 20) % 7);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                // 全局分配器失败时不返回，直接用堆分配以便跳过
+                let Some(ptr) = (unsafe { try_alloc(layout) }) else {
+                    continue;
+                };
+                let ptr = ptr.as_ptr();
+                assert_eq!(ptr as usize % align, 0, "misaligned allocation");
+                let range = ptr as usize..ptr as usize + size;
+                for &(other, other_layout) in live.iter().flatten() {
+                    let other = other as usize..other as usize + other_layout.size();
+                    assert!(
+                        range.end <= other.start || other.end <= range.start,
+                        "allocation {range:#x?} overlaps {other:#x?}"
+                    );
+                }
+                unsafe { ptr.write_bytes(slot as u8, size) };
+                live[slot] = Some((ptr, layout));
+            }
+        }
+        let stats = heap_stats();
+        let expected = live.iter().flatten().map(|(_, l)| l.size()).sum::<usize>();
+        assert_eq!(stats.allocated_bytes, baseline + expected);
+        assert_eq!(stats.free_bytes, stats.total_bytes - stats.allocated_bytes);
+    }
+    for (slot, entry) in live.iter_mut().enumerate() {
+        if let Some((ptr, layout)) = entry.take() {
+            unsafe { release(slot, ptr, layout) };
+        }
+    }
+    assert_eq!(heap_stats().allocated_bytes, baseline, "heap leaked");
+    shared_frame();
+    assert_eq!(
+        heap_stats().allocated_bytes,
+        baseline,
+        "shared frame leaked"
+    );
+    #[cfg(feature = "double_free_check")]
+    double_free();
+}
+
+/// 检查重复释放会被发现。
+///
+/// 真的重复释放会 panic 而关机，因此检查的是释放时使用的同一个判断。宿主机上的测试会真的重复释放。
+#[cfg(feature = "double_free_check")]
+fn double_free() {
+    use crate::free_allowed;
+
+    let layout = Layout::new::<[usize; 8]>();
+    let Some(ptr) = (unsafe { try_alloc(layout) }) else {
+        return;
+    };
+    assert!(
+        unsafe { free_allowed(ptr.as_ptr()) },
+        "free of {ptr:?} rejected"
+    );
+    unsafe { free(ptr, layout) };
+    assert!(
+        !unsafe { free_allowed(ptr.as_ptr()) },
+        "double free of {ptr:?} not detected"
+    );
+}
+
+/// 检查 [`SharedFrame`] 的引用计数变化。
+fn shared_frame() {
+    let Ok(a) = SharedFrame::new() else {
+        return;
+    };
+    assert_eq!(a.ref_count(), 1);
+    let b = a.clone();
+    assert_eq!((a.ref_count(), b.ref_count()), (2, 2));
+    assert_eq!(a.ppn(), b.ppn());
+    // 共享时不能转为独占
+    let a = a.try_unique().err().expect("a shared frame became unique");
+    drop(b);
+    assert_eq!(a.ref_count(), 1);
+    let ppn = a.ppn();
+    let frame = a
+        .try_unique()
+        .ok()
+        .expect("the last holder should be unique");
+    assert_eq!(frame.ppn(), ppn);
+}
+
+/// 检查填充的内容后释放。
+unsafe fn release(slot: usize, ptr: *mut u8, layout: Layout) {
+    let bytes = core::slice::from_raw_parts(ptr, layout.size());
+    assert!(
+        bytes.iter().all(|&b| b == slot as u8),
+        "allocation at {ptr:?} was corrupted"
+    );
+    free(NonNull::new(ptr).unwrap(), layout);
+}
@@ -0,0 +1,86 @@
+//! 宿主机上的测试。
+//!
+//! 堆是全局的，所有测试共用一次初始化的堆，并逐个串行执行。
+
+use crate::{
+    allocate_pages, deallocate_pages, free_page_count, heap_stats, largest_free_block, self_test,
+    set_alloc_limit, transfer, try_init, AllocError, PAGE_BITS,
+};
+use std::sync::{Mutex, MutexGuard, Once};
+
+/// 测试用的堆大小。
+const HEAP_BYTES: usize = 4 << 20;
+
+/// 初始化堆，返回串行执行测试的锁。
+fn heap() -> MutexGuard<'static, ()> {
+    static INIT: Once = Once::new();
+    static SERIAL: Mutex<()> = Mutex::new(());
+    INIT.call_once(|| {
+        let layout = std::alloc::Layout::from_size_align(HEAP_BYTES, HEAP_BYTES).unwrap();
+        let base = unsafe { std::alloc::alloc(layout) };
+        assert!(!base.is_null());
+        try_init(base as usize).unwrap();
+        unsafe { transfer(core::slice::from_raw_parts_mut(base, HEAP_BYTES)) };
+    });
+    // 之前的测试 panic 时锁中毒，但堆的状态仍然一致
+    SERIAL.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[test]
+fn stress() {
+    let _heap = heap();
+    for seed in [1, 0x2545_f491_4f6c_dd1d, 0xdead_beef] {
+        self_test(10_000, seed);
+    }
+}
+
+#[test]
+fn stats() {
+    let _heap = heap();
+    let stats = heap_stats();
+    // 启用 `double_free_check` 时位图占用内存块的末尾
+    assert!(stats.total_bytes <= HEAP_BYTES);
+    assert_eq!(stats.free_bytes, stats.total_bytes - stats.allocated_bytes);
+    assert!(stats.largest_free_block <= stats.free_bytes);
+    assert_eq!(free_page_count(), largest_free_block() >> PAGE_BITS);
+}
+
+#[test]
+fn alloc_limit() {
+    let _heap = heap();
+    let base = heap_stats().allocated_bytes;
+    set_alloc_limit(Some(base + (4 << PAGE_BITS)));
+    let pages = (0..4)
+        .map(|_| allocate_pages(1).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(allocate_pages(1), Err(AllocError::OutOfMemory));
+    set_alloc_limit(None);
+    let page = allocate_pages(1).unwrap();
+    for ptr in pages.into_iter().chain([page]) {
+        unsafe { deallocate_pages(ptr, 1) };
+    }
+    assert_eq!(heap_stats().allocated_bytes, base);
+}
+
+#[cfg(feature = "double_free_check")]
+#[test]
+#[should_panic(expected = "double free")]
+fn double_free() {
+    let _heap = heap();
+    let layout = core::alloc::Layout::new::<[usize; 8]>();
+    let ptr = unsafe { crate::try_alloc(layout) }.unwrap();
+    unsafe {
+        crate::free(ptr, layout);
+        crate::free(ptr, layout);
+    }
+}
+
+#[cfg(feature = "double_free_check")]
+#[test]
+#[should_panic(expected = "unallocated memory")]
+fn free_foreign() {
+    let _heap = heap();
+    let layout = core::alloc::Layout::new::<[usize; 8]>();
+    let ptr = core::ptr::NonNull::new(unsafe { std::alloc::alloc(layout) }).unwrap();
+    unsafe { crate::free(ptr, layout) };
+}
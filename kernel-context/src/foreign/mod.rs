@@ -185,6 +185,9 @@ impl PortalText {
 
 /// 切换地址空间然后 sret。
 /// 地址空间恢复后一切都会恢复原状。
+///
+/// 进入时只刷新目标地址空间标识的地址转换缓存，保留其他地址空间和全局页的缓存。
+/// 目标与其他地址空间共用 0 号标识时无法区分，全部刷新；返回时同理，离开的地址空间使用 0 号标识才全部刷新。
 #[naked]
 unsafe extern "C" fn foreign_execute(ctx: *mut PortalCache) {
     core::arch::asm!(
@@ -197,9 +200,19 @@ unsafe extern "C" fn foreign_execute(ctx: *mut PortalCache) {
         // 交换地址空间
         "   ld    a1, 2*8(a0)
             csrrw a1, satp, a1
-            sfence.vma
             sd    a1, 2*8(a0)
         ",
+        // 按目标地址空间标识刷新
+        "   csrr  a1, satp
+            srli  a1, a1, 44
+            slli  a1, a1, 48
+            srli  a1, a1, 48
+            bnez  a1, 2f
+            sfence.vma
+            j     3f
+         2: sfence.vma zero, a1
+         3:
+        ",
         // 加载 sstatus
         "   ld    a1, 3*8(a0)
             csrw      sstatus, a1
@@ -237,9 +250,16 @@ unsafe extern "C" fn foreign_execute(ctx: *mut PortalCache) {
         // 恢复地址空间
         "   ld    a1, 2*8(a0)
             csrrw a1, satp, a1
-            sfence.vma
             sd    a1, 2*8(a0)
         ",
+        // 离开的地址空间使用 0 号标识时全部刷新
+        "   srli  a1, a1, 44
+            slli  a1, a1, 48
+            srli  a1, a1, 48
+            bnez  a1, 4f
+            sfence.vma
+         4:
+        ",
         // 恢复通用寄存器
         "   ld    a1, 1*8(a0)",
         // 恢复陷入入口
@@ -0,0 +1,92 @@
+use core::fmt;
+use page_table::{VAddr, VmMeta, PPN, VPN};
+
+/// 指针没有按页对齐，附带这个地址。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Unaligned(pub usize);
+
+/// 计算按页对齐的指针所在的虚页号。
+///
+/// 与 `VAddr::new(ptr as _).floor()` 不同，指针不在页首时返回错误而不是截去页内偏移。
+/// 用于认定指针指向新分配的页的地方。
+#[inline]
+pub fn vpn_of_aligned<Meta: VmMeta>(ptr: *const u8) -> Result<VPN<Meta>, Unaligned> {
+    let addr = ptr as usize;
+    if addr & ((1 << Meta::PAGE_BITS) - 1) == 0 {
+        Ok(VAddr::<Meta>::new(addr).floor())
+    } else {
+        Err(Unaligned(addr))
+    }
+}
+
+/// 从 `start` 起连续的 `count` 个虚页号。
+///
+/// 到达地址空间顶端 `VPN::MAX` 后停止，因此可能少于 `count` 个。`VPN` 本身可以与 `usize` 相加。
+#[inline]
+pub fn vpn_range<Meta: VmMeta>(start: VPN<Meta>, count: usize) -> impl Iterator<Item = VPN<Meta>> {
+    let end = start
+        .val()
+        .saturating_add(count)
+        .min(VPN::<Meta>::MAX.val() + 1);
+    (start.val()..end).map(VPN::new)
+}
+
+/// 从 `start` 起连续的 `count` 个物理页号。
+///
+/// 到达 `usize` 的上限后停止。可以与 [`vpn_range`] 逐项组合，同时遍历一段映射的虚页和物理页。
+#[inline]
+pub fn ppn_range<Meta: VmMeta>(start: PPN<Meta>, count: usize) -> impl Iterator<Item = PPN<Meta>> {
+    (start.val()..start.val().saturating_add(count)).map(PPN::new)
+}
+
+/// 支持的最大页表级数。
+const MAX_LEVELS: usize = 5;
+
+/// 虚地址在各级页表中的索引和页内偏移，见 [`vpn_indices`]。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct VpnIndices {
+    indices: [usize; MAX_LEVELS],
+    levels: usize,
+    offset: usize,
+}
+
+impl VpnIndices {
+    /// 各级页表中的索引，第 `i` 项是 `i` 级页表中的索引，0 级是叶子页表。
+    #[inline]
+    pub fn as_slice(&self) -> &[usize] {
+        &self.indices[..self.levels]
+    }
+
+    /// 页内偏移。
+    #[inline]
+    pub fn page_offset(&self) -> usize {
+        self.offset
+    }
+}
+
+/// 从根页表到叶子页表依次显示，例如 `[2]0x0 [1]0x1 [0]0x10 +0x8`。
+impl fmt::Display for VpnIndices {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (level, index) in self.as_slice().iter().enumerate().rev() {
+            write!(f, "[{level}]{index:#x} ")?;
+        }
+        write!(f, "+{:#x}", self.offset)
+    }
+}
+
+/// 计算 `addr` 在各级页表中的索引和页内偏移。
+///
+/// 索引与 `VPN::index_in` 一致，因此适用于任意级数的分页方式，最多 5 级。
+pub fn vpn_indices<Meta: VmMeta>(addr: VAddr<Meta>) -> VpnIndices {
+    assert!(Meta::MAX_LEVEL < MAX_LEVELS);
+    let vpn = addr.floor();
+    let mut indices = [0; MAX_LEVELS];
+    for (level, index) in indices.iter_mut().enumerate().take(Meta::MAX_LEVEL + 1) {
+        *index = vpn.index_in(level);
+    }
+    VpnIndices {
+        indices,
+        levels: Meta::MAX_LEVEL + 1,
+        offset: addr.offset(),
+    }
+}
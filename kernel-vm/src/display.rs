@@ -0,0 +1,45 @@
+use core::fmt::{self, Write};
+use page_table::{Pte, VmFlags, VmMeta};
+
+/// 以 `DAGUXWRV` 的紧凑形式显示页属性，未设置的位显示为 `-`。
+#[derive(Clone, Copy)]
+pub struct FlagsDisplay<Meta: VmMeta>(pub VmFlags<Meta>);
+
+impl<Meta: VmMeta> fmt::Display for FlagsDisplay<Meta> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, c) in "DAGUXWRV".chars().enumerate() {
+            let bit = unsafe { VmFlags::<Meta>::from_raw(1 << (7 - i)) };
+            f.write_char(if self.0.contains(bit) { c } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+impl<Meta: VmMeta> fmt::Debug for FlagsDisplay<Meta> {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// 以 `PPN(0x80201) [--G-XWRV]` 的形式显示页表项。
+#[derive(Clone, Copy)]
+pub struct PteDisplay<Meta: VmMeta>(pub Pte<Meta>);
+
+impl<Meta: VmMeta> fmt::Display for PteDisplay<Meta> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PPN({:#x}) [{}]",
+            self.0.ppn().val(),
+            FlagsDisplay(self.0.flags())
+        )
+    }
+}
+
+impl<Meta: VmMeta> fmt::Debug for PteDisplay<Meta> {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
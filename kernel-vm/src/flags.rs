@@ -0,0 +1,150 @@
+use core::marker::PhantomData;
+use page_table::{Pte, VmFlags, VmMeta};
+
+/// 页属性位不合法。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InvalidFlags {
+    /// 设置了页表项属性范围之外的位，附带这些位。
+    UndefinedBits(usize),
+    /// 可写但不可读，RISC-V 保留了这种组合。
+    WriteWithoutRead,
+}
+
+/// 页表项属性占用的位，包括 `DAGUXWRV` 和留给软件的 2 位 RSW。
+pub const FLAGS_MASK: usize = (1 << 10) - 1;
+
+const V: usize = 1 << 0;
+const R: usize = 1 << 1;
+const W: usize = 1 << 2;
+const X: usize = 1 << 3;
+const U: usize = 1 << 4;
+const G: usize = 1 << 5;
+/// RSW 的高位，在无效的页表项中标记按需分配的页。
+const LAZY: usize = 1 << 9;
+/// `UXWR` 权限位。
+pub(crate) const PERM_MASK: usize = 0b1_1110;
+
+/// 检查后将 `bits` 转换为页属性。
+///
+/// 与 `VmFlags::from_raw` 不同，拒绝定义范围之外的位和可写不可读的组合。
+pub const fn try_flags_from_raw<Meta: VmMeta>(bits: usize) -> Result<VmFlags<Meta>, InvalidFlags> {
+    if bits & !FLAGS_MASK != 0 {
+        Err(InvalidFlags::UndefinedBits(bits & !FLAGS_MASK))
+    } else if bits & (R | W) == W {
+        Err(InvalidFlags::WriteWithoutRead)
+    } else {
+        Ok(unsafe { VmFlags::from_raw(bits) })
+    }
+}
+
+/// 标记按需分配的页的属性。
+///
+/// 只设置 RSW 的高位而不设置 V，因此硬件将页表项视为无效，访问时引发缺页。
+/// RSW 的低位留给页管理器标记所有权。
+#[inline]
+pub const fn lazy_flags<Meta: VmMeta>() -> VmFlags<Meta> {
+    unsafe { VmFlags::from_raw(LAZY) }
+}
+
+/// 判断页表项是否是 [`lazy_flags`] 标记的按需分配的页。
+#[inline]
+pub fn is_lazy_reserved<Meta: VmMeta>(pte: Pte<Meta>) -> bool {
+    !pte.is_valid() && pte.flags().contains(lazy_flags())
+}
+
+/// 按名字逐位设置页属性。
+///
+/// 所有方法都是 `const fn`，可以用在常量和静态变量中：
+///
+/// ```ignore
+/// const TEXT: VmFlags<Sv39> = FlagsBuilder::new().executable().readable().valid().build();
+/// ```
+#[derive(Clone, Copy)]
+pub struct FlagsBuilder<Meta: VmMeta> {
+    bits: usize,
+    _meta: PhantomData<Meta>,
+}
+
+impl<Meta: VmMeta> FlagsBuilder<Meta> {
+    /// 空的页属性。
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            bits: 0,
+            _meta: PhantomData,
+        }
+    }
+
+    #[inline]
+    const fn with(self, bit: usize) -> Self {
+        Self {
+            bits: self.bits | bit,
+            _meta: PhantomData,
+        }
+    }
+
+    /// 设置 `V` 位。
+    #[inline]
+    pub const fn valid(self) -> Self {
+        self.with(V)
+    }
+
+    /// 设置 `R` 位。
+    #[inline]
+    pub const fn readable(self) -> Self {
+        self.with(R)
+    }
+
+    /// 设置 `W` 位。
+    #[inline]
+    pub const fn writable(self) -> Self {
+        self.with(W)
+    }
+
+    /// 设置 `X` 位。
+    #[inline]
+    pub const fn executable(self) -> Self {
+        self.with(X)
+    }
+
+    /// 设置 `U` 位。
+    #[inline]
+    pub const fn user(self) -> Self {
+        self.with(U)
+    }
+
+    /// 设置 `G` 位。
+    #[inline]
+    pub const fn global(self) -> Self {
+        self.with(G)
+    }
+
+    /// 生成页属性。
+    ///
+    /// 设置的位都在定义范围内，不检查可写不可读的组合。
+    #[inline]
+    pub const fn build(self) -> VmFlags<Meta> {
+        unsafe { VmFlags::from_raw(self.bits) }
+    }
+}
+
+impl<Meta: VmMeta> Default for FlagsBuilder<Meta> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Meta: VmMeta> From<FlagsBuilder<Meta>> for VmFlags<Meta> {
+    #[inline]
+    fn from(builder: FlagsBuilder<Meta>) -> Self {
+        builder.build()
+    }
+}
+
+/// 取出页属性的原始位。
+pub(crate) fn flags_bits<Meta: VmMeta>(flags: VmFlags<Meta>) -> usize {
+    (0..10)
+        .filter(|i| flags.contains(unsafe { VmFlags::from_raw(1 << i) }))
+        .fold(0, |bits, i| bits | (1 << i))
+}
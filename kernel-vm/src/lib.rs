@@ -1,12 +1,29 @@
-//! 内核虚存管理。
+﻿//! 内核虚存管理。
 
 #![no_std]
 #![deny(warnings, missing_docs)]
 
+mod addr;
+mod display;
+mod flags;
+#[cfg(feature = "self-test")]
+mod self_test;
 mod space;
+mod table;
 
 pub extern crate page_table;
-pub use space::AddressSpace;
+pub use addr::{ppn_range, vpn_indices, vpn_of_aligned, vpn_range, Unaligned, VpnIndices};
+pub use display::{FlagsDisplay, PteDisplay};
+pub use flags::{
+    is_lazy_reserved, lazy_flags, try_flags_from_raw, FlagsBuilder, InvalidFlags, FLAGS_MASK,
+};
+#[cfg(feature = "self-test")]
+pub use self_test::self_test;
+pub use space::{
+    predict_table_pages, AccessViolation, AddressSpace, MapError, MappedRegion, PageFault,
+    PageFaultKind, ProtectError, WalkStop, WalkTrace,
+};
+pub use table::{try_page_table, TableError};
 
 use core::ptr::NonNull;
 use page_table::{Pte, VmFlags, VmMeta, PPN};
@@ -37,6 +54,14 @@ pub trait PageManager<Meta: VmMeta> {
     /// 为地址空间分配 `len` 个物理页。
     fn allocate(&mut self, len: usize, flags: &mut VmFlags<Meta>) -> NonNull<u8>;
 
+    /// 为地址空间分配 `len` 个物理页，失败时返回 `None`。
+    ///
+    /// 默认调用 [`PageManager::allocate`]，因此不会失败。能发现内存不足的页管理器应该实现这个方法。
+    #[inline]
+    fn try_allocate(&mut self, len: usize, flags: &mut VmFlags<Meta>) -> Option<NonNull<u8>> {
+        Some(self.allocate(len, flags))
+    }
+
     /// 从地址空间释放 `pte` 指示的 `len` 个物理页。
     fn deallocate(&mut self, pte: Pte<Meta>, len: usize) -> usize;
 
@@ -0,0 +1,91 @@
+use crate::{AddressSpace, MapError, PageManager};
+use page_table::{VAddr, VmFlags, VmMeta, PPN, VPN};
+
+/// 检查页表遍历逻辑。
+///
+/// 在一个临时地址空间中映射几组已知的虚页到物理页和一个一级页表中的大页，逐一检查翻译出的物理地址和页属性，
+/// 再检查未映射的地址、重叠的映射和撤销映射后的地址翻译失败。只写页表，不访问映射的物理页。
+///
+/// 临时地址空间的页表页由 `M` 分配，检查结束时用 [`AddressSpace::release`] 归还，根页表由 `M` 释放。页表遍历有错时 panic。
+pub fn self_test<Meta: VmMeta, M: PageManager<Meta>>() {
+    const CASES: [(usize, usize, usize); 4] = [
+        (0x0, 0x8_0000, 1),
+        (0x1ff, 0x8_1000, 2),
+        (0x4_0000, 0x8_2000, 3),
+        (0x3ff_0000, 0x8_3000, 1),
+    ];
+    let flags: [VmFlags<Meta>; 4] = [
+        VmFlags::build_from_str("X_RV"),
+        VmFlags::build_from_str("_WRV"),
+        VmFlags::build_from_str("U__RV"),
+        VmFlags::build_from_str("UXWRV"),
+    ];
+    let mut space = AddressSpace::<Meta, M>::new();
+    for (&(vpn, ppn, count), &flags) in CASES.iter().zip(&flags) {
+        space.map_extern(VPN::new(vpn)..VPN::new(vpn + count), PPN::new(ppn), flags);
+    }
+    for (&(vpn, ppn, count), &flags) in CASES.iter().zip(&flags) {
+        for i in 0..count {
+            let offset = 0x123;
+            let vaddr = VAddr::<Meta>::new(((vpn + i) << Meta::PAGE_BITS) + offset);
+            let (paddr, ans) = space
+                .translate_phys(vaddr)
+                .unwrap_or_else(|| panic!("vpn {:#x} should be mapped", vpn + i));
+            assert_eq!(paddr, ((ppn + i) << Meta::PAGE_BITS) + offset);
+            assert!(ans.contains(flags));
+        }
+        // 映射范围之后的一页没有映射
+        assert!(space.is_range_free(VPN::new(vpn + count), 1));
+    }
+    // 一级页表中的大页，大页中间的虚地址翻译时要加上它在大页中的页号
+    let (vpn, ppn, index) = (0x8_0000, 0x9_0000, 0x5);
+    let flags = VmFlags::build_from_str("_WRV");
+    space
+        .map_huge(VPN::new(vpn), 1, PPN::new(ppn), flags)
+        .unwrap();
+    let vaddr = VAddr::<Meta>::new(((vpn + index) << Meta::PAGE_BITS) + 0x123);
+    let (paddr, ans) = space
+        .translate_phys(vaddr)
+        .expect("huge page should be mapped");
+    assert_eq!(paddr, ((ppn + index) << Meta::PAGE_BITS) + 0x123);
+    assert!(ans.contains(flags));
+    // 单个页的映射，已映射时只有允许覆盖才替换
+    let (vpn, ppn) = (VPN::new(0x2_0000), 0x8_4000);
+    let flags = VmFlags::build_from_str("_WRV");
+    let vaddr = VAddr::<Meta>::new(vpn.base().val());
+    space.map_page(vpn, PPN::new(ppn), flags, false).unwrap();
+    assert_eq!(
+        space.translate_phys(vaddr).unwrap().0,
+        ppn << Meta::PAGE_BITS
+    );
+    assert_eq!(
+        space.map_page(vpn, PPN::new(ppn + 1), flags, false),
+        Err(MapError::AlreadyMapped(ppn))
+    );
+    space.map_page(vpn, PPN::new(ppn + 1), flags, true).unwrap();
+    assert_eq!(
+        space.translate_phys(vaddr).unwrap().0,
+        (ppn + 1) << Meta::PAGE_BITS
+    );
+    // 与已有映射重叠时失败，撤销已经建立的映射和为此新分配的页表
+    let (vpn, ppn, _) = CASES[3];
+    let tables = space.table_pages();
+    assert_eq!(
+        space.try_map_extern(
+            VPN::new(vpn - 1)..VPN::new(vpn + 1),
+            PPN::new(0x8_5000),
+            flags
+        ),
+        Err(MapError::AlreadyMapped(ppn))
+    );
+    assert!(space.is_range_free(VPN::new(vpn - 1), 1));
+    assert_eq!(space.table_pages(), tables);
+    // 第二组跨过两个叶子页表，后一个只有它的映射，撤销后被回收
+    let (vpn, _, count) = CASES[1];
+    let tables = space.table_pages();
+    space.unmap(VPN::new(vpn)..VPN::new(vpn + count));
+    assert!(space.is_range_free(VPN::new(vpn), count));
+    assert_eq!(space.table_pages(), tables - 1);
+    space.release();
+    assert_eq!(space.table_pages(), 0);
+}
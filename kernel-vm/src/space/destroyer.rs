@@ -0,0 +1,174 @@
+﻿use super::{empty_pte, is_leaf, Shape};
+use crate::{is_lazy_reserved, AddressSpace, PageManager};
+use core::ptr::NonNull;
+use page_table::{Decorator, Pos, Pte, Update, VmMeta, VPN};
+
+/// 在自己分配的页表中查找下一个要清除的页表项。
+///
+/// 只读地遍历，跳过无效的中间页表项；路径上遇到大页或别的地址空间的页表时跳过它覆盖的整个范围。
+pub(super) struct Finder<'a, Meta: VmMeta, M: PageManager<Meta>> {
+    space: &'a AddressSpace<Meta, M>,
+    level: usize,
+    vpn: VPN<Meta>,
+    end: VPN<Meta>,
+    ans: Found<Meta>,
+}
+
+/// 查找的结果。
+pub(super) enum Found<Meta: VmMeta> {
+    /// `vpn` 所在的页表项有效或预留，路径上都是自己分配的页表。
+    Entry(VPN<Meta>),
+    /// 路径被挡住，从 `vpn` 继续查找。
+    Skip(VPN<Meta>),
+    /// 范围内没有更多页表项。
+    End,
+}
+
+impl<'a, Meta: VmMeta, M: PageManager<Meta>> Finder<'a, Meta, M> {
+    /// 从 `vpn` 所在的 `level` 级页表项开始，查找到 `end` 之前。
+    #[inline]
+    pub fn new(
+        space: &'a AddressSpace<Meta, M>,
+        level: usize,
+        vpn: VPN<Meta>,
+        end: VPN<Meta>,
+    ) -> Self {
+        Self {
+            space,
+            level,
+            vpn,
+            end,
+            ans: Found::End,
+        }
+    }
+
+    #[inline]
+    pub fn ans(self) -> Found<Meta> {
+        self.ans
+    }
+
+    /// 跳过 `vpn` 所在的 `level` 级页表项覆盖的范围，返回下一个查找的位置。
+    fn skip(&mut self, level: usize) -> Pos<Meta> {
+        self.vpn = next_entry(self.vpn, level);
+        if self.vpn.val() < self.end.val() {
+            Pos::new(self.vpn, self.level)
+        } else {
+            Pos::stop()
+        }
+    }
+}
+
+impl<'a, Meta: VmMeta, M: PageManager<Meta>> page_table::Visitor<Meta> for Finder<'a, Meta, M> {
+    #[inline]
+    fn arrive(&mut self, pte: Pte<Meta>, _target_hint: Pos<Meta>) -> Pos<Meta> {
+        if pte.is_valid() || (self.level == 0 && is_lazy_reserved(pte)) {
+            self.ans = Found::Entry(self.vpn);
+            Pos::stop()
+        } else {
+            self.skip(self.level)
+        }
+    }
+
+    #[inline]
+    fn meet(
+        &mut self,
+        level: usize,
+        pte: Pte<Meta>,
+        _target_hint: Pos<Meta>,
+    ) -> Option<NonNull<Pte<Meta>>> {
+        if is_leaf(&pte) || !self.space.owns_table(&pte) {
+            self.ans = Found::Skip(next_entry(self.vpn, level));
+            None
+        } else {
+            Some(self.space.page_manager.p_to_v(pte.ppn()))
+        }
+    }
+
+    #[inline]
+    fn block(&mut self, level: usize, _pte: Pte<Meta>, _target: Pos<Meta>) -> Pos<Meta> {
+        self.skip(level)
+    }
+}
+
+/// 清除 [`Finder`] 找到的一个页表项。
+///
+/// 最低一级清除叶子页表项和预留标记，物理页由调用者处理；更高的级别回收已经没有任何页表项的自有页表，
+/// 并清除整个落在范围内的大页叶子。
+pub(super) struct Destroyer<'a, Meta: VmMeta, M: PageManager<Meta>> {
+    space: &'a mut AddressSpace<Meta, M>,
+    level: usize,
+    whole: bool,
+    ans: Option<Pte<Meta>>,
+}
+
+impl<'a, Meta: VmMeta, M: PageManager<Meta>> Destroyer<'a, Meta, M> {
+    /// `whole` 表示这个页表项覆盖的范围整个要清除。
+    #[inline]
+    pub fn new(space: &'a mut AddressSpace<Meta, M>, level: usize, whole: bool) -> Self {
+        Self {
+            space,
+            level,
+            whole,
+            ans: None,
+        }
+    }
+
+    /// 返回清除的有效页表项。
+    #[inline]
+    pub fn ans(self) -> Option<Pte<Meta>> {
+        self.ans
+    }
+}
+
+impl<Meta: VmMeta, M: PageManager<Meta>> Decorator<Meta> for Destroyer<'_, Meta, M> {
+    #[inline]
+    fn arrive(&mut self, pte: &mut Pte<Meta>, _target_hint: Pos<Meta>) -> Pos<Meta> {
+        if self.level == 0 {
+            if pte.is_valid() {
+                self.ans = Some(*pte);
+            }
+            *pte = empty_pte();
+        } else if pte.is_valid() && is_leaf(pte) {
+            if self.whole {
+                self.ans = Some(*pte);
+                *pte = empty_pte();
+            }
+        } else if pte.is_valid() && self.space.owns_table(pte) {
+            let bits = Shape::<Meta>::LEVEL_BITS;
+            let table = self.space.page_manager.p_to_v::<Pte<Meta>>(pte.ppn());
+            let entries = unsafe { core::slice::from_raw_parts(table.as_ptr(), 1 << bits) };
+            if entries
+                .iter()
+                .all(|pte| !pte.is_valid() && !is_lazy_reserved(*pte))
+            {
+                self.ans = Some(*pte);
+                self.space.free_table(pte.ppn());
+                *pte = empty_pte();
+            }
+        }
+        Pos::stop()
+    }
+
+    #[inline]
+    fn meet(
+        &mut self,
+        _level: usize,
+        pte: Pte<Meta>,
+        _target_hint: Pos<Meta>,
+    ) -> Option<NonNull<Pte<Meta>>> {
+        // 路径已经由 `Finder` 检查过
+        Some(self.space.page_manager.p_to_v(pte.ppn()))
+    }
+
+    #[inline]
+    fn block(&mut self, _level: usize, _pte: Pte<Meta>, _target_hint: Pos<Meta>) -> Update<Meta> {
+        Update::Target(Pos::stop())
+    }
+}
+
+/// `vpn` 所在的 `level` 级页表项之后的下一个页表项覆盖的起始虚页号。
+#[inline]
+pub(super) fn next_entry<Meta: VmMeta>(vpn: VPN<Meta>, level: usize) -> VPN<Meta> {
+    let shift = Shape::<Meta>::LEVEL_BITS * level;
+    VPN::new(((vpn.val() >> shift) + 1) << shift)
+}
@@ -1,4 +1,5 @@
-﻿use crate::{AddressSpace, PageManager};
+﻿use super::{is_leaf, MapError};
+use crate::{is_lazy_reserved, AddressSpace, PageManager};
 use core::{ops::Range, ptr::NonNull};
 use page_table::{Decorator, Pos, Pte, Update, VmFlags, VmMeta, PPN};
 
@@ -6,7 +7,8 @@ pub(super) struct Mapper<'a, Meta: VmMeta, M: PageManager<Meta>> {
     space: &'a mut AddressSpace<Meta, M>,
     range: Range<PPN<Meta>>,
     flags: VmFlags<Meta>,
-    done: bool,
+    mapped: usize,
+    ans: Result<(), MapError>,
 }
 
 impl<'a, Meta: VmMeta, M: PageManager<Meta>> Mapper<'a, Meta, M> {
@@ -20,24 +22,32 @@ impl<'a, Meta: VmMeta, M: PageManager<Meta>> Mapper<'a, Meta, M> {
             space,
             range,
             flags,
-            done: false,
+            mapped: 0,
+            ans: Ok(()),
         }
     }
 
+    /// 返回已经建立的映射数，以及映射是否全部完成。
     #[inline]
-    pub fn ans(self) -> bool {
-        self.done
+    pub fn ans(self) -> (usize, Result<(), MapError>) {
+        (self.mapped, self.ans)
     }
 }
 
 impl<Meta: VmMeta, M: PageManager<Meta>> Decorator<Meta> for Mapper<'_, Meta, M> {
     #[inline]
     fn arrive(&mut self, pte: &mut Pte<Meta>, target_hint: Pos<Meta>) -> Pos<Meta> {
-        assert!(!pte.is_valid());
+        if pte.is_valid() {
+            self.ans = Err(MapError::AlreadyMapped(pte.ppn().val()));
+            return Pos::stop();
+        } else if is_lazy_reserved(*pte) {
+            self.ans = Err(MapError::Reserved);
+            return Pos::stop();
+        }
         *pte = self.flags.build_pte(self.range.start);
         self.range.start += 1;
+        self.mapped += 1;
         if self.range.start == self.range.end {
-            self.done = true;
             Pos::stop()
         } else {
             target_hint.next()
@@ -47,23 +57,30 @@ impl<Meta: VmMeta, M: PageManager<Meta>> Decorator<Meta> for Mapper<'_, Meta, M>
     #[inline]
     fn meet(
         &mut self,
-        _level: usize,
+        level: usize,
         pte: Pte<Meta>,
         _target_hint: Pos<Meta>,
     ) -> Option<NonNull<Pte<Meta>>> {
-        if self.space.page_manager.check_owned(pte) {
-            Some(self.space.page_manager.p_to_v(pte.ppn()))
-        } else {
+        // 只经过自己分配的页表，不向大页或别的地址空间的页表中添加映射
+        if is_leaf(&pte) || !self.space.owns_table(&pte) {
+            self.ans = Err(MapError::Blocked(level));
             None
+        } else {
+            Some(self.space.page_manager.p_to_v(pte.ppn()))
         }
     }
 
     #[inline]
     fn block(&mut self, _level: usize, pte: Pte<Meta>, _target_hint: Pos<Meta>) -> Update<Meta> {
         assert!(!pte.is_valid());
+        // 中间页表项不带 G 位，见 `AddressSpace::clone_global_entries`
         let mut flags = VmFlags::VALID;
-        let page = self.space.page_manager.allocate(1, &mut flags);
+        let Some(page) = self.space.page_manager.try_allocate(1, &mut flags) else {
+            self.ans = Err(MapError::OutOfMemory);
+            return Update::Target(Pos::stop());
+        };
         let ppn = self.space.page_manager.v_to_p(page);
+        self.space.tables.insert(ppn.val());
         Update::Pte(flags.build_pte(ppn), page.cast())
     }
 }
@@ -1,29 +1,125 @@
+﻿mod destroyer;
 mod mapper;
 mod visitor;
 
 extern crate alloc;
 
-use crate::PageManager;
-use alloc::vec::Vec;
-use core::{fmt, ops::Range, ptr::NonNull};
+use crate::{
+    flags::{flags_bits, PERM_MASK},
+    is_lazy_reserved, lazy_flags, try_flags_from_raw, vpn_range, InvalidFlags, PageManager,
+};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use core::{fmt, marker::PhantomData, ops::Range, ptr::NonNull};
+use destroyer::{next_entry, Destroyer, Finder, Found};
 use mapper::Mapper;
-use page_table::{PageTable, PageTableFormatter, Pos, VAddr, VmFlags, VmMeta, PPN, VPN};
+use page_table::{PageTable, PageTableFormatter, Pos, Pte, VAddr, VmFlags, VmMeta, PPN, VPN};
 use visitor::Visitor;
 
 /// 地址空间。
+///
+/// 地址空间记录页管理器为它分配的每一块物理页。一块中的页全部撤销映射之后，整块归还给页管理器。
+/// 地址空间没有实现 [`Drop`]，需要回收内存时在丢弃之前调用 [`AddressSpace::release`]。
 pub struct AddressSpace<Meta: VmMeta, M: PageManager<Meta>> {
     /// 虚拟地址块
     pub areas: Vec<Range<VPN<Meta>>>,
     page_manager: M,
+    /// 自己分配的中间页表的物理页号。不在其中的页表属于别的地址空间，不修改也不释放。
+    tables: BTreeSet<usize>,
+    /// 自己分配的物理页块，起始物理页号映射到块的页数和仍然映射着的页数。
+    frames: BTreeMap<usize, (usize, usize)>,
+}
+
+/// 地址空间中一段连续映射的虚存区域。
+pub struct MappedRegion<Meta: VmMeta> {
+    /// 虚页号范围。
+    pub vpn: Range<VPN<Meta>>,
+    /// 起始物理页号，区域映射到连续的物理页上。
+    pub ppn: PPN<Meta>,
+    /// 页属性。
+    pub flags: VmFlags<Meta>,
+}
+
+/// 访问地址空间失败。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PageFault {
+    /// 出错的虚地址。
+    pub vaddr: usize,
+    /// 出错的原因。
+    pub kind: PageFaultKind,
+}
+
+/// 访问地址空间失败的原因。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PageFaultKind {
+    /// 虚地址未映射。
+    Unmapped,
+    /// 页属性不满足要求。
+    Forbidden,
+}
+
+/// 修改页属性失败的原因。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProtectError {
+    /// 虚页未映射。
+    Unmapped,
+    /// 新的页属性不合法。
+    Invalid(InvalidFlags),
+}
+
+/// 建立映射失败的原因。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MapError {
+    /// 虚页已经映射，附带已映射的物理页号。
+    AlreadyMapped(usize),
+    /// 虚页已经预留为按需分配的页。
+    Reserved,
+    /// 路径上遇到大页或不归地址空间管理的页表，附带所在的级别。
+    Blocked(usize),
+    /// 物理页或页表分配失败。
+    OutOfMemory,
+}
+
+/// 用户访问检查失败的位置。
+pub struct AccessViolation<Meta: VmMeta> {
+    /// 检查失败的页表项所在的级别。
+    pub level: usize,
+    /// 检查失败的页表项的属性。
+    pub flags: VmFlags<Meta>,
+}
+
+/// 翻译一个虚地址时经过的页表项。
+pub struct WalkTrace<Meta: VmMeta> {
+    /// 从根页表开始，每一级的级别和页表项。
+    pub ptes: Vec<(usize, Pte<Meta>)>,
+    /// 停止的原因。
+    pub stop: WalkStop,
+}
+
+/// 页表遍历停止的原因。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WalkStop {
+    /// 最后一个页表项无效。
+    Invalid,
+    /// 最后一个页表项是叶子。
+    Leaf,
+    /// 到达最低一级仍然不是叶子，页表已损坏。
+    NoLeaf,
 }
 
 impl<Meta: VmMeta, M: PageManager<Meta>> AddressSpace<Meta, M> {
     /// 创建新地址空间。
     #[inline]
     pub fn new() -> Self {
+        // 在编译时检查页表的形状
+        let _ = Shape::<Meta>::LEVEL_BITS;
         Self {
             areas: Vec::new(),
             page_manager: M::new_root(),
+            tables: BTreeSet::new(),
+            frames: BTreeMap::new(),
         }
     }
 
@@ -33,6 +129,12 @@ impl<Meta: VmMeta, M: PageManager<Meta>> AddressSpace<Meta, M> {
         self.page_manager.root_ppn()
     }
 
+    /// 现有的自己分配的中间页表页数，不包括根页表。
+    #[inline]
+    pub fn table_pages(&self) -> usize {
+        self.tables.len()
+    }
+
     /// 地址空间根页表
     #[inline]
     pub fn root(&self) -> PageTable<Meta> {
@@ -40,53 +142,333 @@ impl<Meta: VmMeta, M: PageManager<Meta>> AddressSpace<Meta, M> {
     }
 
     /// 向地址空间增加映射关系。
+    ///
+    /// 映射失败时回滚并 panic，见 [`AddressSpace::try_map_extern`]。
     pub fn map_extern(&mut self, range: Range<VPN<Meta>>, pbase: PPN<Meta>, flags: VmFlags<Meta>) {
-        self.areas.push(range.start..range.end);
-        let count = range.end.val() - range.start.val();
+        let (start, end) = (range.start.val(), range.end.val());
+        if let Err(e) = self.try_map_extern(range, pbase, flags) {
+            panic!("failed to map vpn {start:#x}..{end:#x}: {e:?}");
+        }
+    }
+
+    /// 向地址空间增加映射关系。
+    ///
+    /// 范围内有已经映射或预留的页，或者遇到大页、不归地址空间管理的页表时映射失败。
+    /// 失败时撤销这次调用建立的映射，回收为它新分配的中间页表，地址空间恢复原状。
+    pub fn try_map_extern(
+        &mut self,
+        range: Range<VPN<Meta>>,
+        pbase: PPN<Meta>,
+        flags: VmFlags<Meta>,
+    ) -> Result<(), MapError> {
+        self.map_pages(range.clone(), pbase, flags)?;
+        self.areas.push(range);
+        Ok(())
+    }
+
+    /// 用 [`Mapper`] 将 `range` 映射到从 `pbase` 开始的物理页，不记录到 `areas`。失败时撤销这次建立的映射。
+    fn map_pages(
+        &mut self,
+        range: Range<VPN<Meta>>,
+        pbase: PPN<Meta>,
+        flags: VmFlags<Meta>,
+    ) -> Result<(), MapError> {
+        let count = range.end.val().saturating_sub(range.start.val());
+        if count == 0 {
+            return Ok(());
+        }
         let mut root = self.root();
         let mut mapper = Mapper::new(self, pbase..pbase + count, flags);
         root.walk_mut(Pos::new(range.start, 0), &mut mapper);
-        if !mapper.ans() {
-            // 映射失败，需要回滚吗？
-            todo!()
+        let (mapped, ans) = mapper.ans();
+        if ans.is_err() {
+            // 失败的页可能已经为它分配了中间页表
+            let failed = range.start + mapped;
+            self.clear_range(range.start..failed);
+            self.prune(failed..failed + 1);
+        }
+        ans
+    }
+
+    /// 找到 `vpn` 在 `target` 级页表中的页表项，缺少的中间页表由页管理器分配。
+    ///
+    /// 只经过自己分配的页表，路径上遇到大页或别的地址空间的页表时返回所在的级别。
+    fn entry_mut(&mut self, vpn: VPN<Meta>, target: usize) -> Result<NonNull<Pte<Meta>>, MapError> {
+        let mut table = self.page_manager.root_ptr();
+        for level in (target + 1..=Meta::MAX_LEVEL).rev() {
+            let pte = unsafe { &mut *table.as_ptr().add(vpn.index_in(level)) };
+            if !pte.is_valid() {
+                // 中间页表项不带 G 位，见 `AddressSpace::clone_global_entries`
+                let mut flags = VmFlags::VALID;
+                let page = self
+                    .page_manager
+                    .try_allocate(1, &mut flags)
+                    .ok_or(MapError::OutOfMemory)?;
+                let ppn = self.page_manager.v_to_p(page);
+                *pte = flags.build_pte(ppn);
+                self.tables.insert(ppn.val());
+            } else if is_leaf(pte) || !self.owns_table(pte) {
+                return Err(MapError::Blocked(level));
+            }
+            table = self.page_manager.p_to_v(pte.ppn());
+        }
+        Ok(unsafe { NonNull::new_unchecked(table.as_ptr().add(vpn.index_in(target))) })
+    }
+
+    /// 将从 `vpn` 开始的 `level` 级大页映射到从 `ppn` 开始的物理页，缺少的中间页表由页管理器分配。
+    ///
+    /// `vpn` 和 `ppn` 都要按大页对齐。大页不记录到 `areas`，也不归地址空间所有，用于自检。
+    pub(crate) fn map_huge(
+        &mut self,
+        vpn: VPN<Meta>,
+        level: usize,
+        ppn: PPN<Meta>,
+        flags: VmFlags<Meta>,
+    ) -> Result<(), MapError> {
+        let mask = (1 << (level * Shape::<Meta>::LEVEL_BITS)) - 1;
+        assert!(level <= Meta::MAX_LEVEL && vpn.val() & mask == 0 && ppn.val() & mask == 0);
+        let pte = match self.entry_mut(vpn, level) {
+            Ok(mut ptr) => unsafe { ptr.as_mut() },
+            Err(e) => {
+                self.prune(vpn..vpn + 1);
+                return Err(e);
+            }
+        };
+        if pte.is_valid() {
+            return Err(MapError::AlreadyMapped(pte.ppn().val()));
         }
+        *pte = flags.build_pte(ppn);
+        Ok(())
     }
 
     /// 分配新的物理页，拷贝数据并建立映射。
+    ///
+    /// 映射失败时回滚并 panic，见 [`AddressSpace::try_map`]。
     pub fn map(
         &mut self,
         range: Range<VPN<Meta>>,
         data: &[u8],
         offset: usize,
-        mut flags: VmFlags<Meta>,
+        flags: VmFlags<Meta>,
     ) {
+        let (start, end) = (range.start.val(), range.end.val());
+        if let Err(e) = self.try_map(range, data, offset, flags) {
+            panic!("failed to map vpn {start:#x}..{end:#x}: {e:?}");
+        }
+    }
+
+    /// 分配新的物理页，拷贝数据并建立映射。
+    ///
+    /// `data` 从 `range` 起始处偏移 `offset` 字节放置，其余部分清零。整个范围的物理页一次分配。
+    /// 除了与 [`AddressSpace::try_map_extern`] 相同的原因，页管理器分配物理页或页表失败时也返回错误。
+    /// 失败时归还这次调用分配的物理页和页表，地址空间恢复原状。
+    pub fn try_map(
+        &mut self,
+        range: Range<VPN<Meta>>,
+        data: &[u8],
+        offset: usize,
+        mut flags: VmFlags<Meta>,
+    ) -> Result<(), MapError> {
         let count = range.end.val() - range.start.val();
         let size = count << Meta::PAGE_BITS;
         assert!(size >= data.len() + offset);
-        let page = self.page_manager.allocate(count, &mut flags);
-        unsafe {
-            use core::slice::from_raw_parts_mut as slice;
-            let mut ptr = page.as_ptr();
-            slice(ptr, offset).fill(0);
-            ptr = ptr.add(offset);
-            slice(ptr, data.len()).copy_from_slice(data);
-            ptr = ptr.add(data.len());
-            slice(ptr, page.as_ptr().add(size).offset_from(ptr) as _).fill(0);
+        if count == 0 {
+            self.areas.push(range);
+            return Ok(());
+        }
+        let page = self
+            .page_manager
+            .try_allocate(count, &mut flags)
+            .ok_or(MapError::OutOfMemory)?;
+        unsafe { fill_pages(page, size, data, offset) };
+        let ppn = self.page_manager.v_to_p(page);
+        if let Err(e) = self.map_pages(range.clone(), ppn, flags) {
+            self.page_manager.deallocate(flags.build_pte(ppn), count);
+            return Err(e);
+        }
+        self.frames.insert(ppn.val(), (count, count));
+        self.areas.push(range);
+        Ok(())
+    }
+
+    /// 为 `range` 预留按需分配的页，失败的原因与 [`AddressSpace::try_map_extern`] 相同。
+    ///
+    /// 预留的页表项无效，翻译时仍视为未映射；访问引发缺页后由 [`AddressSpace::fill_lazy`] 分配物理页。
+    /// 预留的范围与映射一样记录在 `areas` 中，撤销时也用 [`AddressSpace::unmap`]。`cloneself` 不支持预留的范围。
+    #[inline]
+    pub fn reserve_lazy(&mut self, range: Range<VPN<Meta>>) -> Result<(), MapError> {
+        self.try_map_extern(range, PPN::new(0), lazy_flags())
+    }
+
+    /// 如果 `vpn` 是预留的按需分配的页，为它分配一个清零的物理页，以 `flags` 映射，返回 `true`。
+    ///
+    /// 不是预留的页或分配物理页失败时返回 `false`。
+    /// 修改的是之前无效的页表项，调用者仍要刷新 `vpn` 的地址转换缓存。
+    pub fn fill_lazy(&mut self, vpn: VPN<Meta>, mut flags: VmFlags<Meta>) -> bool {
+        let Some(mut ptr) = self.lazy_ptr(vpn) else {
+            return false;
+        };
+        let Some(page) = self.page_manager.try_allocate(1, &mut flags) else {
+            return false;
+        };
+        unsafe { core::slice::from_raw_parts_mut(page.as_ptr(), 1 << Meta::PAGE_BITS).fill(0) };
+        let ppn = self.page_manager.v_to_p(page);
+        unsafe { *ptr.as_mut() = flags.build_pte(ppn) };
+        self.frames.insert(ppn.val(), (1, 1));
+        true
+    }
+
+    /// 将虚页 `vpn` 映射到物理页 `ppn`，缺少的中间页表由页管理器分配。
+    ///
+    /// 不需要为单个页的映射编写 [`Decorator`](page_table::Decorator)。`vpn` 已经映射时，
+    /// `overwrite` 为 `false` 则返回错误，为 `true` 则替换原来的映射，此时调用者要刷新 `vpn` 的地址转换缓存。
+    /// 原来的物理页属于自己分配的块时，与撤销映射一样计入这一块，见 [`AddressSpace`]。
+    /// 预留的按需分配的页视为未映射。失败时回收为此新分配的中间页表。
+    pub fn map_page(
+        &mut self,
+        vpn: VPN<Meta>,
+        ppn: PPN<Meta>,
+        flags: VmFlags<Meta>,
+        overwrite: bool,
+    ) -> Result<(), MapError> {
+        let leaf = match self.entry_mut(vpn, 0) {
+            Ok(mut ptr) => unsafe { ptr.as_mut() },
+            Err(e) => {
+                self.prune(vpn..vpn + 1);
+                return Err(e);
+            }
+        };
+        if leaf.is_valid() {
+            if !overwrite {
+                return Err(MapError::AlreadyMapped(leaf.ppn().val()));
+            }
+            let old = *leaf;
+            *leaf = flags.build_pte(ppn);
+            self.release_frame(old);
+            return Ok(());
+        } else if !is_lazy_reserved(*leaf) {
+            self.areas.push(vpn..vpn + 1);
+        }
+        *leaf = flags.build_pte(ppn);
+        Ok(())
+    }
+
+    /// 撤销地址空间中 `range` 的映射关系，返回撤销的页数。
+    ///
+    /// 清除叶子页表项和预留标记，撤销后不再有任何页表项的中间页表归还给页管理器。
+    /// 自己分配的物理页块全部撤销后归还，`map_extern` 映射的外部物理页由调用者自行管理。范围内未映射的页跳过。
+    pub fn unmap(&mut self, range: Range<VPN<Meta>>) -> usize {
+        self.areas
+            .retain(|area| !(range.start <= area.start && area.end <= range.end));
+        self.clear_range(range)
+    }
+
+    /// 清除 `range` 中的叶子页表项和预留标记，然后回收空的页表，返回清除的有效叶子页表项数。
+    fn clear_range(&mut self, range: Range<VPN<Meta>>) -> usize {
+        let cleared = self.destroy(range.clone(), 0);
+        self.prune(range);
+        cleared
+    }
+
+    /// 回收 `range` 经过的、已经没有任何页表项的自有页表。根页表不回收。
+    fn prune(&mut self, range: Range<VPN<Meta>>) {
+        // 从低到高回收，低一级的页表回收之后高一级的页表才可能为空
+        for level in 1..=Meta::MAX_LEVEL {
+            self.destroy(range.clone(), level);
+        }
+    }
+
+    /// 用 [`Destroyer`] 逐个清除与 `range` 相交的 `level` 级页表项，返回清除的有效页表项数。
+    ///
+    /// 由 [`Finder`] 找到下一个要清除的页表项，只经过自己分配的页表。
+    fn destroy(&mut self, range: Range<VPN<Meta>>, level: usize) -> usize {
+        let mut vpn = range.start;
+        let mut count = 0;
+        while vpn.val() < range.end.val() {
+            let mut finder = Finder::new(self, level, vpn, range.end);
+            self.root().walk(Pos::new(vpn, level), &mut finder);
+            vpn = match finder.ans() {
+                Found::Entry(found) => {
+                    // 大页的叶子整个落在范围内才清除
+                    let shift = Shape::<Meta>::LEVEL_BITS * level;
+                    let start = found.val() >> shift << shift;
+                    let whole =
+                        range.start.val() <= start && start + (1 << shift) <= range.end.val();
+                    let mut root = self.root();
+                    let mut destroyer = Destroyer::new(self, level, whole);
+                    root.walk_mut(Pos::new(found, level), &mut destroyer);
+                    if let Some(pte) = destroyer.ans() {
+                        if level == 0 {
+                            self.release_frame(pte);
+                        }
+                        count += 1;
+                    }
+                    next_entry(found, level)
+                }
+                Found::Skip(next) => next,
+                Found::End => break,
+            };
+        }
+        count
+    }
+
+    /// `pte` 是否指向自己分配的页表。
+    #[inline]
+    fn owns_table(&self, pte: &Pte<Meta>) -> bool {
+        self.tables.contains(&pte.ppn().val())
+    }
+
+    /// 撤销映射的叶子 `pte` 指向自己分配的块时计入这一块，块中的页都已撤销时归还整块。
+    ///
+    /// 回滚中撤销的页还没有记录为块，由调用者归还。
+    fn release_frame(&mut self, pte: Pte<Meta>) {
+        if !self.page_manager.check_owned(pte) {
+            return;
+        }
+        let ppn = pte.ppn().val();
+        let Some((&start, block)) = self.frames.range_mut(..=ppn).next_back() else {
+            return;
+        };
+        let count = block.0;
+        if ppn >= start + count {
+            return;
         }
-        self.map_extern(range, self.page_manager.v_to_p(page), flags)
+        block.1 -= 1;
+        if block.1 == 0 {
+            self.frames.remove(&start);
+            self.page_manager
+                .deallocate(pte.flags().build_pte(PPN::new(start)), count);
+        }
+    }
+
+    /// 撤销所有映射，归还自己分配的物理页和中间页表，之后地址空间仍然可以使用。
+    ///
+    /// 根页表保留，由页管理器释放。复制进来的共享页表项（例如 [`AddressSpace::clone_global_entries`]）
+    /// 指向别的地址空间的页表，不受影响。
+    pub fn release(&mut self) {
+        self.areas.clear();
+        let bits = Shape::<Meta>::LEVEL_BITS * (Meta::MAX_LEVEL + 1);
+        self.clear_range(VPN::new(0)..VPN::new(1 << bits));
+        debug_assert!(self.frames.is_empty() && self.tables.is_empty());
+    }
+
+    /// 归还一个自己分配的页表页。
+    fn free_table(&mut self, ppn: PPN<Meta>) {
+        self.tables.remove(&ppn.val());
+        self.page_manager
+            .deallocate(VmFlags::VALID.build_pte(ppn), 1);
     }
 
     /// 检查 `flags` 的属性要求，然后将地址空间中的一个虚地址翻译成当前地址空间中的指针。
+    ///
+    /// 虚地址落在大页中时，由大页的起始物理页号加上虚地址在大页中的页号得到所在的物理页。
     pub fn translate<T>(&self, addr: VAddr<Meta>, flags: VmFlags<Meta>) -> Option<NonNull<T>> {
-        let mut visitor = Visitor::new(self);
-        self.root().walk(Pos::new(addr.floor(), 0), &mut visitor);
-        visitor
-            .ans()
-            .filter(|pte| pte.flags().contains(flags))
-            .map(|pte| unsafe {
+        self.leaf(addr.floor())
+            .filter(|(_, leaf_flags)| leaf_flags.contains(flags))
+            .map(|(ppn, _)| unsafe {
                 NonNull::new_unchecked(
                     self.page_manager
-                        .p_to_v::<u8>(pte.ppn())
+                        .p_to_v::<u8>(ppn)
                         .as_ptr()
                         .add(addr.offset())
                         .cast(),
@@ -94,44 +476,372 @@ impl<Meta: VmMeta, M: PageManager<Meta>> AddressSpace<Meta, M> {
             })
     }
 
+    /// 将地址空间中的一个虚地址翻译成物理地址，并返回所在页的属性。
+    ///
+    /// 虚地址未映射时返回 `None`。大页中的虚地址与 [`AddressSpace::translate`] 一样翻译。
+    pub fn translate_phys(&self, addr: VAddr<Meta>) -> Option<(usize, VmFlags<Meta>)> {
+        self.leaf(addr.floor())
+            .map(|(ppn, flags)| ((ppn.val() << Meta::PAGE_BITS) + addr.offset(), flags))
+    }
+
+    /// 遍历地址空间中所有已映射的虚存区域。
+    ///
+    /// 按虚页号顺序遍历页表中的有效叶子，虚页连续、物理页连续且属性相同的叶子合并为一个区域。
+    /// 因此结果反映修改属性、覆盖和部分解除映射之后的实际映射，按需分配而尚未分配的页不在其中。
+    pub fn regions(&self) -> impl Iterator<Item = MappedRegion<Meta>> + '_ {
+        let bits = Shape::<Meta>::LEVEL_BITS;
+        let mut regions: Vec<MappedRegion<Meta>> = Vec::new();
+        self.for_each_pte(|level, vpn, pte| {
+            if !pte.is_valid() || !is_leaf(pte) {
+                return;
+            }
+            let count = 1 << (bits * level);
+            if let Some(last) = regions.last_mut() {
+                let len = last.vpn.end.val() - last.vpn.start.val();
+                if last.vpn.end.val() == vpn.val()
+                    && last.ppn.val() + len == pte.ppn().val()
+                    && flags_bits(last.flags) == flags_bits(pte.flags())
+                {
+                    last.vpn.end = vpn + count;
+                    return;
+                }
+            }
+            regions.push(MappedRegion {
+                vpn: vpn..vpn + count,
+                ppn: pte.ppn(),
+                flags: pte.flags(),
+            });
+        });
+        regions.into_iter()
+    }
+
+    /// 判断从 `start` 开始的 `count` 个虚页是否都未映射。
+    ///
+    /// 遇到第一个已映射的页就返回 `false`。
+    pub fn is_range_free(&self, start: VPN<Meta>, count: usize) -> bool {
+        vpn_range(start, count).all(|vpn| self.leaf(vpn).is_none())
+    }
+
+    /// 从地址空间的 `addr` 处拷贝数据填满 `dst`，返回拷贝的字节数。
+    ///
+    /// 逐页翻译，每一页都要满足 `flags` 的属性要求，因此数据可以跨越不连续的物理页。
+    /// 遇到未映射或属性不满足的页时返回出错的虚地址。
+    pub fn copy_from(
+        &self,
+        addr: VAddr<Meta>,
+        dst: &mut [u8],
+        flags: VmFlags<Meta>,
+    ) -> Result<usize, PageFault> {
+        let mut copied = 0;
+        while copied < dst.len() {
+            let vaddr = VAddr::<Meta>::new(addr.val() + copied);
+            let fault = |kind| PageFault {
+                vaddr: vaddr.val(),
+                kind,
+            };
+            let (ppn, leaf_flags) = self
+                .leaf(vaddr.floor())
+                .ok_or_else(|| fault(PageFaultKind::Unmapped))?;
+            if !leaf_flags.contains(flags) {
+                return Err(fault(PageFaultKind::Forbidden));
+            }
+            let len = ((1 << Meta::PAGE_BITS) - vaddr.offset()).min(dst.len() - copied);
+            unsafe {
+                let src = self.page_manager.p_to_v::<u8>(ppn).as_ptr();
+                let src = core::slice::from_raw_parts(src.add(vaddr.offset()), len);
+                dst[copied..][..len].copy_from_slice(src);
+            }
+            copied += len;
+        }
+        Ok(copied)
+    }
+
+    /// 遍历所有页表中的每一个页表项，无论是否有效。
+    ///
+    /// `f` 的参数依次是页表项所在的级别、页表项覆盖的起始虚页号和页表项本身。
+    /// 只读遍历，不分配内存，只要页表页可以通过 [`PageManager::p_to_v`] 访问就可以调用。
+    pub fn for_each_pte<F: FnMut(usize, VPN<Meta>, &Pte<Meta>)>(&self, mut f: F) {
+        self.visit_table(self.page_manager.root_ptr(), Meta::MAX_LEVEL, 0, &mut f);
+    }
+
+    fn visit_table<F: FnMut(usize, VPN<Meta>, &Pte<Meta>)>(
+        &self,
+        table: NonNull<Pte<Meta>>,
+        level: usize,
+        base: usize,
+        f: &mut F,
+    ) {
+        let bits = Shape::<Meta>::LEVEL_BITS;
+        let entries = unsafe { core::slice::from_raw_parts(table.as_ptr(), 1 << bits) };
+        for (i, pte) in entries.iter().enumerate() {
+            let vpn = base + (i << (bits * level));
+            f(level, VPN::new(vpn), pte);
+            if level > 0 && pte.is_valid() && !is_leaf(pte) {
+                self.visit_table(self.page_manager.p_to_v(pte.ppn()), level - 1, vpn, f);
+            }
+        }
+    }
+
+    /// 将已映射的虚页 `vpn` 的权限改为 `flags` 中的 `UXWR` 位，保留物理页号和其他位。
+    ///
+    /// 不刷新地址转换缓存，成功时返回需要刷新的虚页，由调用者按自己的地址空间标识刷新。
+    pub fn protect(
+        &mut self,
+        vpn: VPN<Meta>,
+        flags: VmFlags<Meta>,
+    ) -> Result<VPN<Meta>, ProtectError> {
+        let mut ptr = self.leaf_ptr(vpn).ok_or(ProtectError::Unmapped)?;
+        let pte = unsafe { ptr.as_mut() };
+        let bits = (flags_bits(pte.flags()) & !PERM_MASK) | (flags_bits(flags) & PERM_MASK);
+        let flags = try_flags_from_raw(bits).map_err(ProtectError::Invalid)?;
+        *pte = flags.build_pte(pte.ppn());
+        Ok(vpn)
+    }
+
+    /// 找到 `vpn` 对应的有效叶子页表项。
+    fn leaf_ptr(&self, vpn: VPN<Meta>) -> Option<NonNull<Pte<Meta>>> {
+        let mut table = self.page_manager.root_ptr();
+        let mut level = Meta::MAX_LEVEL;
+        loop {
+            let ptr = unsafe { NonNull::new_unchecked(table.as_ptr().add(vpn.index_in(level))) };
+            let pte = unsafe { *ptr.as_ptr() };
+            if !pte.is_valid() {
+                return None;
+            } else if is_leaf(&pte) {
+                return Some(ptr);
+            } else if level == 0 {
+                return None;
+            }
+            table = self.page_manager.p_to_v(pte.ppn());
+            level -= 1;
+        }
+    }
+
+    /// 找到 `vpn` 对应的按需分配的页表项。
+    fn lazy_ptr(&self, vpn: VPN<Meta>) -> Option<NonNull<Pte<Meta>>> {
+        let mut table = self.page_manager.root_ptr();
+        let mut level = Meta::MAX_LEVEL;
+        loop {
+            let ptr = unsafe { NonNull::new_unchecked(table.as_ptr().add(vpn.index_in(level))) };
+            let pte = unsafe { *ptr.as_ptr() };
+            if level == 0 {
+                return Some(ptr).filter(|_| is_lazy_reserved(pte));
+            } else if !pte.is_valid() || is_leaf(&pte) {
+                return None;
+            }
+            table = self.page_manager.p_to_v(pte.ppn());
+            level -= 1;
+        }
+    }
+
+    /// 记录翻译 `addr` 时每一级经过的页表项，以及停止的原因。
+    ///
+    /// 可用于区分缺页是由于缺少中间页表还是缺少叶子页表项。
+    pub fn resolve_path(&self, addr: VAddr<Meta>) -> WalkTrace<Meta> {
+        let vpn = addr.floor();
+        let mut table = self.page_manager.root_ptr();
+        let mut level = Meta::MAX_LEVEL;
+        let mut ptes = Vec::new();
+        loop {
+            let pte = unsafe { *table.as_ptr().add(vpn.index_in(level)) };
+            ptes.push((level, pte));
+            let stop = if !pte.is_valid() {
+                WalkStop::Invalid
+            } else if is_leaf(&pte) {
+                WalkStop::Leaf
+            } else if level == 0 {
+                WalkStop::NoLeaf
+            } else {
+                table = self.page_manager.p_to_v(pte.ppn());
+                level -= 1;
+                continue;
+            };
+            break WalkTrace { ptes, stop };
+        }
+    }
+
+    /// 检查 `addr` 所在的页是否允许用户态以 `need` 访问。
+    ///
+    /// 与 [`AddressSpace::translate`] 不同，要求叶子页表项带 U 位，因此内核专用的页即便已映射也会被拒绝。
+    /// 失败时返回无效、缺少属性或页表损坏的页表项所在的级别和属性。系统调用检查用户传入的指针时使用。
+    pub fn assert_user_accessible(
+        &self,
+        addr: VAddr<Meta>,
+        need: VmFlags<Meta>,
+    ) -> Result<(), AccessViolation<Meta>> {
+        let need = need | VmFlags::build_from_str("U____");
+        let vpn = addr.floor();
+        let mut table = self.page_manager.root_ptr();
+        let mut level = Meta::MAX_LEVEL;
+        loop {
+            let pte = unsafe { *table.as_ptr().add(vpn.index_in(level)) };
+            if pte.is_valid() && is_leaf(&pte) && pte.flags().contains(need) {
+                return Ok(());
+            } else if !pte.is_valid() || is_leaf(&pte) || level == 0 {
+                return Err(AccessViolation {
+                    level,
+                    flags: pte.flags(),
+                });
+            }
+            table = self.page_manager.p_to_v(pte.ppn());
+            level -= 1;
+        }
+    }
+
+    /// 查询 `vpn` 所在的物理页号和叶子页表项的属性。
+    ///
+    /// 逐级向下，停在第一个有效的叶子处。`level` 级的叶子覆盖 `1 << (level * LEVEL_BITS)` 个虚页，
+    /// 加上 `vpn` 在其中的页号才是 `vpn` 所在的物理页。
+    #[inline]
+    fn leaf(&self, vpn: VPN<Meta>) -> Option<(PPN<Meta>, VmFlags<Meta>)> {
+        let mut visitor = Visitor::new(self);
+        self.root().walk(Pos::new(vpn, 0), &mut visitor);
+        visitor.ans().map(|(pte, level)| {
+            let mask = (1 << (level * Shape::<Meta>::LEVEL_BITS)) - 1;
+            (pte.ppn() + (vpn.val() & mask), pte.flags())
+        })
+    }
+
+    /// 将根页表中整棵子树都是全局映射的页表项复制到 `dst` 的根页表的相同位置。
+    ///
+    /// 只复制指向下级页表的引用，不复制下级页表，因此 `dst` 与自己共享这部分映射，适合内核共享给所有用户地址空间的映射。
+    /// G 位只设置在叶子上，中间页表项带 G 位会要求其下之后添加的映射也都是全局的。
+    /// 因此逐棵子树检查，其中的有效叶子全部带 G 位时才复制它的根页表项。`dst` 中已经有效的项不覆盖。
+    ///
+    /// 共享的下级页表属于自己，必须比所有共享它们的地址空间活得更久；`dst` 不会向其中添加映射，也不会释放它们。
+    pub fn clone_global_entries(&self, dst: &mut AddressSpace<Meta, M>) {
+        let src = self.root();
+        let mut dst = dst.root();
+        let bits = Shape::<Meta>::LEVEL_BITS;
+        for i in 0..1 << bits {
+            let pte = src[i];
+            if pte.is_valid() && !dst[i].is_valid() && self.is_global(&pte, Meta::MAX_LEVEL) {
+                dst[i] = pte;
+            }
+        }
+    }
+
+    /// 有效的 `level` 级页表项是带 G 位的叶子，或者指向的子树中有效的叶子全部带 G 位。
+    fn is_global(&self, pte: &Pte<Meta>, level: usize) -> bool {
+        if is_leaf(pte) {
+            return pte.flags().contains(VmFlags::build_from_str("G_____"));
+        } else if level == 0 {
+            return false;
+        }
+        let bits = Shape::<Meta>::LEVEL_BITS;
+        let table = self.page_manager.p_to_v::<Pte<Meta>>(pte.ppn());
+        let entries = unsafe { core::slice::from_raw_parts(table.as_ptr(), 1 << bits) };
+        entries
+            .iter()
+            .filter(|pte| pte.is_valid())
+            .all(|pte| self.is_global(pte, level - 1))
+    }
+
     /// 遍历地址空间，将其中的地址映射添加进自己的地址空间中，重新分配物理页并拷贝所有数据及代码
+    ///
+    /// 每个虚拟地址块一次分配物理页。源地址空间的物理页不一定连续，因此逐页查找、逐页拷贝，保留每一页的属性。
     pub fn cloneself(&self, new_addrspace: &mut AddressSpace<Meta, M>) {
-        let root = self.root();
-        let areas = &self.areas;
-        for (_, range) in areas.iter().enumerate() {
-            let mut visitor = Visitor::new(self);
-            // 虚拟地址块的首地址的 vpn
-            let vpn = range.start;
-            // 利用 visitor 访问页表，并获取这个虚拟地址块的页属性
-            root.walk(Pos::new(vpn, 0), &mut visitor);
-            // 利用 visitor 获取这个虚拟地址块的页属性，以及起始地址
-            let (mut flags, mut data_ptr) = visitor
-                .ans()
-                .filter(|pte| pte.is_valid())
-                .map(|pte| {
-                    (pte.flags(), unsafe {
-                        NonNull::new_unchecked(self.page_manager.p_to_v::<u8>(pte.ppn()).as_ptr())
-                    })
-                })
-                .unwrap();
-            let vpn_range = range.start..range.end;
+        for range in &self.areas {
             // 虚拟地址块中页数量
             let count = range.end.val() - range.start.val();
-            let size = count << Meta::PAGE_BITS;
-            // 分配 count 个 flags 属性的物理页面
-            let paddr = new_addrspace.page_manager.allocate(count, &mut flags);
-            let ppn = new_addrspace.page_manager.v_to_p(paddr);
-            unsafe {
-                use core::slice::from_raw_parts_mut as slice;
-                let data = slice(data_ptr.as_mut(), size);
-                let ptr = paddr.as_ptr();
-                slice(ptr, size).copy_from_slice(data);
+            // 分配 count 个物理页面，`owned` 中只有页管理器设置的所有权标记
+            let mut owned = unsafe { VmFlags::from_raw(0) };
+            let page = new_addrspace.page_manager.allocate(count, &mut owned);
+            let pbase = new_addrspace.page_manager.v_to_p(page);
+            for (i, vpn) in vpn_range(range.start, count).enumerate() {
+                // 获取这一页的页属性，以及起始地址
+                let (ppn, mut flags) = self.leaf(vpn).expect("clone an area that is not mapped");
+                unsafe {
+                    let src = self.page_manager.p_to_v::<u8>(ppn).as_ptr();
+                    let dst = page.as_ptr().add(i << Meta::PAGE_BITS);
+                    core::ptr::copy_nonoverlapping(src, dst, 1 << Meta::PAGE_BITS);
+                }
+                flags |= owned;
+                if let Err(e) = new_addrspace.map_pages(vpn..vpn + 1, pbase + i, flags) {
+                    panic!("failed to clone vpn {:#x}: {e:?}", vpn.val());
+                }
             }
-            new_addrspace.map_extern(vpn_range, ppn, flags);
+            new_addrspace.frames.insert(pbase.val(), (count, count));
+            new_addrspace.areas.push(range.clone());
         }
     }
 }
 
+/// 页表的形状。
+///
+/// 根页表和中间页表都假设正好占一页，例如 Sv39 的 512 个 8 字节页表项。
+/// 关联常量在用到时求值，因此换用不满足假设的 [`VmMeta`] 会在编译时报错。
+pub(crate) struct Shape<Meta: VmMeta>(PhantomData<Meta>);
+
+impl<Meta: VmMeta> Shape<Meta> {
+    /// 每级页表的索引位数。
+    pub(crate) const LEVEL_BITS: usize = {
+        let size = core::mem::size_of::<Pte<Meta>>();
+        assert!(size.is_power_of_two() && size <= 1 << Meta::PAGE_BITS);
+        let bits = Meta::PAGE_BITS - size.trailing_zeros() as usize;
+        // 每级页表的项数乘项的大小正好是一页
+        assert!((1 << bits) * size == 1 << Meta::PAGE_BITS);
+        // 各级索引加上页内偏移不超过地址宽度
+        assert!(Meta::PAGE_BITS + bits * (Meta::MAX_LEVEL + 1) <= usize::BITS as usize);
+        bits
+    };
+}
+
+/// 预计在空地址空间中映射 `ranges` 需要的中间页表页数，不包括根页表。
+///
+/// 与 [`AddressSpace::table_pages`] 对照，可以检查映射时是否多分配或少分配了页表。
+pub fn predict_table_pages<Meta: VmMeta>(ranges: &[Range<VPN<Meta>>]) -> usize {
+    let bits = Shape::<Meta>::LEVEL_BITS;
+    (1..=Meta::MAX_LEVEL)
+        .map(|level| {
+            // 这一级的每个页表覆盖 `1 << shift` 个虚页
+            let shift = bits * level;
+            let mut spans = ranges
+                .iter()
+                .filter(|r| r.start.val() < r.end.val())
+                .map(|r| (r.start.val() >> shift, (r.end.val() - 1) >> shift))
+                .collect::<Vec<_>>();
+            spans.sort_unstable();
+            let mut count = 0;
+            let mut next = 0;
+            for (start, end) in spans {
+                let start = start.max(next);
+                if start <= end {
+                    count += end - start + 1;
+                    next = end + 1;
+                }
+            }
+            count
+        })
+        .sum()
+}
+
+/// 在 `page` 起的 `size` 字节中从 `offset` 处填入 `data`，其余清零。
+///
+/// # Safety
+///
+/// `page` 必须指向 `size` 个可写的字节，且 `offset + data.len()` 不超过 `size`。
+unsafe fn fill_pages(page: NonNull<u8>, size: usize, data: &[u8], offset: usize) {
+    let dst = core::slice::from_raw_parts_mut(page.as_ptr(), size);
+    dst[..offset].fill(0);
+    dst[offset..][..data.len()].copy_from_slice(data);
+    dst[offset + data.len()..].fill(0);
+}
+
+/// 全零的无效页表项。
+#[inline]
+fn empty_pte<Meta: VmMeta>() -> Pte<Meta> {
+    unsafe { VmFlags::from_raw(0) }.build_pte(PPN::new(0))
+}
+
+/// 有效且不可读写执行的页表项指向下一级页表，否则是叶子。
+#[inline]
+fn is_leaf<Meta: VmMeta>(pte: &Pte<Meta>) -> bool {
+    pte.flags().contains(VmFlags::build_from_str("R_"))
+        || pte.flags().contains(VmFlags::build_from_str("W__"))
+        || pte.flags().contains(VmFlags::build_from_str("X___"))
+}
+
 impl<Meta: VmMeta, P: PageManager<Meta>> fmt::Debug for AddressSpace<Meta, P> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "root: {:#x}", self.root_ppn().val())?;
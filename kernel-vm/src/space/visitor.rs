@@ -1,10 +1,11 @@
-﻿use crate::{AddressSpace, PageManager};
+﻿use super::is_leaf;
+use crate::{AddressSpace, PageManager};
 use core::ptr::NonNull;
 use page_table::{Pos, Pte, VmMeta};
 
 pub(super) struct Visitor<'a, Meta: VmMeta, M: PageManager<Meta>> {
     space: &'a AddressSpace<Meta, M>,
-    ans: Option<Pte<Meta>>,
+    ans: Option<(Pte<Meta>, usize)>,
 }
 
 impl<'a, Meta: VmMeta, M: PageManager<Meta>> Visitor<'a, Meta, M> {
@@ -13,8 +14,9 @@ impl<'a, Meta: VmMeta, M: PageManager<Meta>> Visitor<'a, Meta, M> {
         Self { space, ans: None }
     }
 
+    /// 返回第一个有效的叶子页表项和它所在的级别。
     #[inline]
-    pub const fn ans(self) -> Option<Pte<Meta>> {
+    pub const fn ans(self) -> Option<(Pte<Meta>, usize)> {
         self.ans
     }
 }
@@ -23,7 +25,7 @@ impl<'a, Meta: VmMeta, M: PageManager<Meta>> page_table::Visitor<Meta> for Visit
     #[inline]
     fn arrive(&mut self, pte: Pte<Meta>, _target_hint: Pos<Meta>) -> Pos<Meta> {
         if pte.is_valid() {
-            self.ans = Some(pte);
+            self.ans = Some((pte, 0));
         }
         Pos::stop()
     }
@@ -31,11 +33,17 @@ impl<'a, Meta: VmMeta, M: PageManager<Meta>> page_table::Visitor<Meta> for Visit
     #[inline]
     fn meet(
         &mut self,
-        _level: usize,
+        level: usize,
         pte: Pte<Meta>,
         _target_hint: Pos<Meta>,
     ) -> Option<NonNull<Pte<Meta>>> {
-        Some(self.space.page_manager.p_to_v(pte.ppn()))
+        // 大页的叶子在更高的级别上，停在第一个叶子处
+        if is_leaf(&pte) {
+            self.ans = Some((pte, level));
+            None
+        } else {
+            Some(self.space.page_manager.p_to_v(pte.ppn()))
+        }
     }
 
     #[inline]
@@ -0,0 +1,53 @@
+use crate::{space::Shape, Unaligned};
+use core::{fmt, ptr::NonNull};
+use page_table::{PageTable, Pte, VmMeta, VPN};
+
+/// 构造页表时发现的参数错误。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TableError {
+    /// 级数超过 `Meta::MAX_LEVEL`，附带这个级数。
+    LevelTooHigh(usize),
+    /// 页表指针没有按页对齐。
+    Unaligned(Unaligned),
+    /// 起始虚页号不是这一级页表覆盖范围的整数倍，附带这个虚页号。
+    BaseMisaligned(usize),
+}
+
+impl fmt::Display for TableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::LevelTooHigh(level) => write!(f, "page table level {level} is too high"),
+            Self::Unaligned(Unaligned(addr)) => {
+                write!(f, "page table at {addr:#x} is not page-aligned")
+            }
+            Self::BaseMisaligned(vpn) => {
+                write!(f, "base vpn {vpn:#x} does not match the table's coverage")
+            }
+        }
+    }
+}
+
+/// 检查参数后从 `ptr` 处的 `level` 级页表构造 [`PageTable`]，起始虚页号为 `base`。
+///
+/// `PageTable` 是外部类型，因此以函数的形式提供 `PageTable::from_raw_parts` 的检查版本。
+/// 检查 `level` 不超过最大级数、`ptr` 按页对齐、`base` 对齐到这一级页表覆盖的范围。
+/// 只检查参数，不检查页表的内容，`ptr` 仍须指向有效的页表。频繁调用的地方直接用不检查的版本。
+pub fn try_page_table<Meta: VmMeta>(
+    ptr: NonNull<Pte<Meta>>,
+    base: VPN<Meta>,
+    level: usize,
+) -> Result<PageTable<Meta>, TableError> {
+    if level > Meta::MAX_LEVEL {
+        return Err(TableError::LevelTooHigh(level));
+    }
+    let addr = ptr.as_ptr() as usize;
+    if addr & ((1 << Meta::PAGE_BITS) - 1) != 0 {
+        return Err(TableError::Unaligned(Unaligned(addr)));
+    }
+    // 这一级页表覆盖 `1 << shift` 个虚页，根页表覆盖整个地址空间
+    let shift = Shape::<Meta>::LEVEL_BITS * (level + 1);
+    if base.val() & ((1 << shift) - 1) != 0 {
+        return Err(TableError::BaseMisaligned(base.val()));
+    }
+    Ok(unsafe { PageTable::from_raw_parts(ptr, base, level) })
+}
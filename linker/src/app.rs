@@ -1,4 +1,6 @@
-﻿/// 应用程序元数据。
+﻿use core::ffi::CStr;
+
+/// 应用程序元数据。
 #[repr(C)]
 pub struct AppMeta {
     base: u64,
@@ -17,44 +19,147 @@ impl AppMeta {
         unsafe { &apps }
     }
 
+    /// 链接进来的应用程序数量。
+    ///
+    /// 不超过位置表在应用程序数据范围内能容纳的项数，元数据损坏时也不会越界。
+    #[inline]
+    pub fn count(&self) -> usize {
+        let table = &self.first as *const _ as usize;
+        let capacity = (blob_end().saturating_sub(table) / 8).saturating_sub(1);
+        (self.count as usize).min(capacity)
+    }
+
     /// 遍历链接进来的应用程序。
+    ///
+    /// 遇到位置不合法的应用程序就停止，之后的位置也不可信。
     #[inline]
     pub fn iter(&'static self) -> AppIterator {
         AppIterator { meta: self, i: 0 }
     }
+
+    /// 查询第 `i` 个应用程序的清单。
+    ///
+    /// 清单来自 `user/cases.toml` 中章节的 `expect` 表，没有声明预期行为的应用程序返回 `None`。
+    pub fn manifest(&self, i: usize) -> Option<AppManifest> {
+        #[repr(C)]
+        struct Entry {
+            flags: u64,
+            exit_code: i64,
+            timeout_ticks: u64,
+            name: *const u8,
+        }
+        extern "C" {
+            static app_manifest: Entry;
+        }
+        if i >= self.count() {
+            return None;
+        }
+        let entry = unsafe { &*(&app_manifest as *const Entry).add(i) };
+        if entry.flags & 1 == 0 {
+            return None;
+        }
+        let name = unsafe { CStr::from_ptr(entry.name.cast()) };
+        Some(AppManifest {
+            name: name.to_str().unwrap_or("?"),
+            exit_code: (entry.flags & 2 != 0).then_some(entry.exit_code),
+            timeout_ticks: (entry.flags & 4 != 0).then_some(entry.timeout_ticks),
+        })
+    }
+
+    /// 遍历链接进来的应用程序及其名字。
+    ///
+    /// 遇到位置不合法的应用程序就停止，见 [`AppMeta::iter`]。
+    ///
+    /// # Safety
+    ///
+    /// `names` 指向名字表：按应用程序的顺序紧密排列的 [`AppMeta::count`] 个以 `\0` 结尾的 UTF-8 字符串，
+    /// 即 xtask 生成的 `app_names` 符号。
+    #[inline]
+    pub unsafe fn iter_named(&'static self, names: *const u8) -> NamedAppIterator {
+        NamedAppIterator {
+            apps: self.iter(),
+            name: names,
+        }
+    }
+}
+
+/// 应用程序数据的结束位置，即 xtask 生成的 `apps_end` 符号。
+#[inline]
+fn blob_end() -> usize {
+    extern "C" {
+        static apps_end: u8;
+    }
+    unsafe { &apps_end as *const _ as usize }
+}
+
+/// 应用程序的清单，声明应用程序的预期行为。
+#[derive(Clone, Copy, Debug)]
+pub struct AppManifest {
+    /// 应用程序的名字。
+    pub name: &'static str,
+    /// 预期的退出码。
+    pub exit_code: Option<i64>,
+    /// 超时的时钟中断次数。
+    pub timeout_ticks: Option<u64>,
 }
 
 /// 应用程序迭代器。
 pub struct AppIterator {
     meta: &'static AppMeta,
-    i: u64,
+    i: usize,
 }
 
 impl Iterator for AppIterator {
     type Item = &'static [u8];
 
+    /// 返回下一个应用程序，遇到位置不合法的应用程序就结束遍历。
     fn next(&mut self) -> Option<Self::Item> {
-        if self.i >= self.meta.count {
-            None
-        } else {
-            let i = self.i as usize;
-            self.i += 1;
-            unsafe {
-                let slice = core::slice::from_raw_parts(
-                    &self.meta.first as *const _ as *const usize,
-                    (self.meta.count + 1) as _,
-                );
-                let pos = slice[i];
-                let size = slice[i + 1] - pos;
-                let base = self.meta.base as usize + i * self.meta.step as usize;
-                if base != 0 {
-                    core::ptr::copy_nonoverlapping::<u8>(pos as _, base as _, size);
-                    core::slice::from_raw_parts_mut(base as *mut u8, 0x20_0000)[size..].fill(0);
-                    Some(core::slice::from_raw_parts(base as _, size))
-                } else {
-                    Some(core::slice::from_raw_parts(pos as _, size))
-                }
+        let count = self.meta.count();
+        if self.i >= count {
+            return None;
+        }
+        let i = self.i;
+        self.i += 1;
+        unsafe {
+            let slice = core::slice::from_raw_parts(
+                &self.meta.first as *const _ as *const usize,
+                count + 1,
+            );
+            let pos = slice[i];
+            let end = slice[i + 1];
+            // 应用程序数据在位置表之后、`apps_end` 之前，且位置单调递增
+            if pos < slice.as_ptr_range().end as usize || end < pos || end > blob_end() {
+                self.i = count;
+                return None;
+            }
+            let size = end - pos;
+            let base = self.meta.base as usize + i * self.meta.step as usize;
+            if base != 0 {
+                core::ptr::copy_nonoverlapping::<u8>(pos as _, base as _, size);
+                core::slice::from_raw_parts_mut(base as *mut u8, 0x20_0000)[size..].fill(0);
+                Some(core::slice::from_raw_parts(base as _, size))
+            } else {
+                Some(core::slice::from_raw_parts(pos as _, size))
             }
         }
     }
 }
+
+/// 带名字的应用程序迭代器。
+pub struct NamedAppIterator {
+    apps: AppIterator,
+    /// 下一个名字的位置。
+    name: *const u8,
+}
+
+impl Iterator for NamedAppIterator {
+    type Item = (&'static str, &'static [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // 遍历不跳过应用程序，名字与应用程序一一对应
+        let app = self.apps.next()?;
+        let name = unsafe { CStr::from_ptr(self.name.cast()) };
+        self.name = unsafe { self.name.add(name.to_bytes().len() + 1) };
+        Some((name.to_str().unwrap_or("?"), app))
+    }
+}
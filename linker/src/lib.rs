@@ -18,6 +18,8 @@
 //! linker::boot0!(rust_main; stack = 4 * 4096);
 //! ```
 //!
+//! 启动栈大小也可以在编译时由环境变量指定，见 [`stack_size`]。
+//!
 //! 内核所在内核区域定义成 4 个部分（[`KernelRegionTitle`]）:
 //!
 //! 1. 代码段
@@ -27,6 +29,11 @@
 //!
 //! 启动数据段放在最后，以便启动完成后换栈。届时可放弃启动数据段，将其加入动态内存区。
 //!
+//! 启动数据段之前留有一个保护页，它不属于任何分区，因此不会被映射。启用分页后，启动栈溢出会触发缺页异常，而不是破坏 .bss 段。
+//!
+//! 所有章节共用同一个链接脚本，因此每个章节的内核都留有这一页。不启用分页的章节（ch1 到 ch3）中它只是一页没有用到的内存，
+//! 不提供保护，也不影响内核的其他部分。
+//!
 //! 用 [`KernelLayout`] 结构体定位、保存和访问内核内存布局。
 
 #![no_std]
@@ -34,9 +41,16 @@
 
 mod app;
 
-pub use app::{AppIterator, AppMeta};
+pub use app::{AppIterator, AppManifest, AppMeta, NamedAppIterator};
+
+/// 内核链接到的起始虚地址，与 [`SCRIPT`] 中 `.text` 的地址一致。
+///
+/// 内核在启用分页之前从物理地址运行，与这个地址比较即可得出内核虚地址与物理地址之差。
+pub const KERNEL_BASE: usize = 0x8020_0000;
 
 /// 链接脚本。
+///
+/// `.boot` 段开头的 `. += 4K` 是启动栈之下的保护页，对所有章节都生效，见模块文档。
 pub const SCRIPT: &[u8] = b"\
 OUTPUT_ARCH(riscv)
 SECTIONS {
@@ -62,6 +76,7 @@ SECTIONS {
         __ebss = .;
     }
     .boot : ALIGN(4K) {
+        . += 4K;
         __boot = .;
         KEEP(*(.boot.stack))
     }
@@ -91,6 +106,95 @@ macro_rules! boot0 {
     };
 }
 
+/// 启动栈最多的页数。
+pub const MAX_STACK_PAGES: usize = 1024;
+
+/// 计算启动栈的字节数。
+///
+/// `env` 是十进制的页数，通常来自 `option_env!("KERNEL_STACK_PAGES")`，为 `None` 时使用 `default_pages`。
+/// 页数的合法范围是 `1..=`[`MAX_STACK_PAGES`]，不合法的值会在编译时报错：
+///
+/// ```rust
+/// linker::boot0!(rust_main; stack = linker::stack_size(option_env!("KERNEL_STACK_PAGES"), 4));
+/// ```
+pub const fn stack_size(env: Option<&str>, default_pages: usize) -> usize {
+    let pages = match env {
+        Some(s) => match parse_decimal(s, MAX_STACK_PAGES) {
+            Some(pages) => pages,
+            None => panic!("KERNEL_STACK_PAGES is not a decimal number in range"),
+        },
+        None => default_pages,
+    };
+    assert!(
+        0 < pages && pages <= MAX_STACK_PAGES,
+        "kernel stack pages out of range"
+    );
+    pages * 4096
+}
+
+/// 物理内存最多的 MiB 数。
+pub const MAX_MEMORY_MIB: usize = 4096;
+
+/// 计算内核管理的物理内存字节数。
+///
+/// `env` 是十进制的 MiB 数，通常来自 `option_env!("KERNEL_MEMORY_MIB")`，为 `None` 时使用 `default_mib`。
+/// 合法范围是 `1..=`[`MAX_MEMORY_MIB`]，不合法的值会在编译时报错。
+pub const fn memory_size(env: Option<&str>, default_mib: usize) -> usize {
+    let mib = match env {
+        Some(s) => match parse_decimal(s, MAX_MEMORY_MIB) {
+            Some(mib) => mib,
+            None => panic!("KERNEL_MEMORY_MIB is not a decimal number in range"),
+        },
+        None => default_mib,
+    };
+    assert!(
+        0 < mib && mib <= MAX_MEMORY_MIB,
+        "kernel memory out of range"
+    );
+    mib << 20
+}
+
+/// 频率最大的赫兹数。
+pub const MAX_FREQUENCY_HZ: usize = 1_000_000_000;
+
+/// 计算频率的赫兹数。
+///
+/// `env` 是十进制的赫兹数，通常来自 `option_env!("TIMEBASE_FREQ")` 等环境变量，为 `None` 时使用 `default_hz`。
+/// 合法范围是 `1..=`[`MAX_FREQUENCY_HZ`]，不合法的值会在编译时报错。
+pub const fn frequency(env: Option<&str>, default_hz: usize) -> usize {
+    let hz = match env {
+        Some(s) => match parse_decimal(s, MAX_FREQUENCY_HZ) {
+            Some(hz) => hz,
+            None => panic!("frequency is not a decimal number in range"),
+        },
+        None => default_hz,
+    };
+    assert!(0 < hz && hz <= MAX_FREQUENCY_HZ, "frequency out of range");
+    hz
+}
+
+/// 编译时解析不超过 `max` 的十进制数。
+const fn parse_decimal(s: &str, max: usize) -> Option<usize> {
+    let bytes = s.as_bytes();
+    if bytes.is_empty() {
+        return None;
+    }
+    let mut ans = 0usize;
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        ans = ans * 10 + (b - b'0') as usize;
+        if ans > max {
+            return None;
+        }
+        i += 1;
+    }
+    Some(ans)
+}
+
 /// 内核地址信息。
 #[derive(Debug)]
 pub struct KernelLayout {
@@ -158,14 +262,26 @@ impl KernelLayout {
     }
 
     /// 清零 .bss 段。
+    ///
+    /// 起止地址都按 8 字节对齐时按字清零，否则逐字节清零。
     #[inline]
     pub unsafe fn zero_bss(&self) {
-        let mut ptr = self.sbss as *mut u8;
-        let end = self.ebss as *mut u8;
-        while ptr < end {
-            // **NOTICE** 单核其实无所谓，多核必须 volatile write 其他核才能看见
-            ptr.write_volatile(0);
-            ptr = ptr.offset(1);
+        const WORD: usize = core::mem::size_of::<u64>();
+        if self.sbss % WORD == 0 && self.ebss % WORD == 0 {
+            let mut ptr = self.sbss as *mut u64;
+            let end = self.ebss as *mut u64;
+            while ptr < end {
+                // **NOTICE** 单核其实无所谓，多核必须 volatile write 其他核才能看见
+                ptr.write_volatile(0);
+                ptr = ptr.offset(1);
+            }
+        } else {
+            let mut ptr = self.sbss as *mut u8;
+            let end = self.ebss as *mut u8;
+            while ptr < end {
+                ptr.write_volatile(0);
+                ptr = ptr.offset(1);
+            }
         }
     }
 
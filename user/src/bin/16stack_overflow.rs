@@ -0,0 +1,21 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+/// 每层递归在栈上占用一块不会被优化掉的数组，直到栈溢出。
+fn recurse(depth: usize) -> usize {
+    let frame = core::hint::black_box([depth as u8; 512]);
+    if depth == usize::MAX {
+        return 0;
+    }
+    recurse(depth + 1) + frame[0] as usize
+}
+
+#[no_mangle]
+fn main() -> i32 {
+    println!("Into Test stack_overflow, we will recurse until the stack overflows...");
+    println!("Kernel should kill this application!");
+    recurse(0) as _
+}
@@ -8,6 +8,17 @@ struct Cases {
     base: Option<u64>,
     step: Option<u64>,
     pub cases: Option<Vec<String>>,
+    /// 应用程序的预期行为，键是应用程序名字。
+    expect: Option<HashMap<String, Expect>>,
+}
+
+/// 一个应用程序的预期行为。
+#[derive(Deserialize)]
+struct Expect {
+    /// 预期的退出码。
+    exit: Option<i64>,
+    /// 超时的时钟中断次数。
+    timeout: Option<u64>,
 }
 
 pub struct CasesInfo {
@@ -112,6 +123,46 @@ app_{i}_end:",
         .unwrap();
     });
 
+    // 应用程序数据的结束位置，用于检查位置表
+    writeln!(
+        ld,
+        "
+    .global apps_end
+apps_end:"
+    )
+    .unwrap();
+
+    // 应用程序清单，每项依次是标志、退出码、超时和名字，没有声明预期行为的应用程序标志为 0
+    writeln!(
+        ld,
+        "
+    .section .rodata
+    .align 3
+    .global app_manifest
+app_manifest:"
+    )
+    .unwrap();
+    let names = cases.cases.as_deref().unwrap_or_default();
+    let expect = cases.expect.take().unwrap_or_default();
+    names.iter().enumerate().for_each(|(i, name)| {
+        let (flags, exit, timeout) = match expect.get(name) {
+            Some(e) => (
+                1 | ((e.exit.is_some() as u64) << 1) | ((e.timeout.is_some() as u64) << 2),
+                e.exit.unwrap_or(0),
+                e.timeout.unwrap_or(0),
+            ),
+            None => (0, 0, 0),
+        };
+        writeln!(
+            ld,
+            "    .quad {flags}, {exit}, {timeout}, app_{i}_manifest_name"
+        )
+        .unwrap();
+    });
+    names.iter().enumerate().for_each(|(i, name)| {
+        writeln!(ld, "app_{i}_manifest_name:\n    .string {name:?}").unwrap();
+    });
+
     if ch == 5 {
         writeln!(
             ld,